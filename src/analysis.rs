@@ -0,0 +1,558 @@
+//! static checks over already parsed VM commands, nothing in here generates assembly
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+};
+
+use crate::{Command, CommandType};
+
+/// name used for commands that appear before the first `function` of a file
+pub const GLOBAL_SCOPE: &str = "<global>";
+
+/// a problem one of the checks found, tied to the raw line it was found on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lint {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// follows the stack depth through straight-line code with `Command::stack_effect`. the depth is
+/// relative to the current function's frame (locals included) and becomes unknown at labels,
+/// since another path may jump there with a different depth, and after `goto`/`return`
+#[derive(Debug, Clone)]
+pub struct DepthTracker {
+    depth: Option<i32>,
+}
+
+impl Default for DepthTracker {
+    fn default() -> DepthTracker {
+        DepthTracker::new()
+    }
+}
+
+impl DepthTracker {
+    pub fn new() -> DepthTracker {
+        DepthTracker { depth: Some(0) }
+    }
+
+    /// the depth after the last command stepped over, `None` when it can't be known
+    pub fn depth(&self) -> Option<i32> {
+        self.depth
+    }
+
+    /// moves past `command`, returning the depth after it
+    pub fn step(&mut self, command: &Command) -> Option<i32> {
+        self.depth = match command.command_type {
+            CommandType::Function => Some(command.arg2.unwrap_or(0) as i32),
+            CommandType::Label | CommandType::Goto | CommandType::Return => None,
+            _ => self.depth.zip(command.stack_effect()).map(|(d, e)| d + e),
+        };
+
+        self.depth
+    }
+}
+
+/// walks each function and returns the deepest the stack gets relative to the function's frame
+/// (locals included), in order of appearance.
+///
+/// this is best-effort: branches are followed forward by remembering the depth at each `goto` and
+/// `if-goto` and taking the max of all known depths when the label is reached. a label only
+/// reachable by a backwards jump has an unknown depth, so the code after it isn't counted.
+pub fn max_stack_depth(commands: &[(usize, Command)]) -> Vec<(String, usize)> {
+    let mut result = Vec::new();
+    let mut function = GLOBAL_SCOPE.to_string();
+    let mut in_scope = false;
+    let mut depth = Some(0);
+    let mut max = 0;
+    let mut branch_depths: HashMap<&str, i32> = HashMap::new();
+
+    for (_, command) in commands {
+        let label = command.arg1.as_deref().unwrap_or_default();
+
+        match command.command_type {
+            CommandType::Function => {
+                if in_scope {
+                    result.push((function, max as usize));
+                }
+
+                function = label.to_string();
+                depth = Some(command.arg2.unwrap_or(0) as i32);
+                max = depth.unwrap_or(0);
+                branch_depths.clear();
+            }
+            CommandType::Label => {
+                depth = depth.max(branch_depths.get(label).copied());
+            }
+            CommandType::Goto => {
+                if let Some(depth) = depth {
+                    let joined = branch_depths.entry(label).or_insert(depth);
+                    *joined = depth.max(*joined);
+                }
+                depth = None;
+            }
+            CommandType::Return => depth = None,
+            _ => {
+                depth = depth.zip(command.stack_effect()).map(|(d, e)| d + e);
+
+                if let (CommandType::If, Some(depth)) = (&command.command_type, depth) {
+                    let joined = branch_depths.entry(label).or_insert(depth);
+                    *joined = depth.max(*joined);
+                }
+            }
+        }
+
+        in_scope = true;
+        max = max.max(depth.unwrap_or(0));
+    }
+
+    if in_scope {
+        result.push((function, max as usize));
+    }
+
+    result
+}
+
+/// warns about every function whose last command isn't a `return` or `goto`, meaning execution
+/// can run off its end into whatever code follows. only the straight-line fallthrough at the very
+/// end is checked, a `return` that is jumped over still counts. when the file has fewer `return`s
+/// than functions, which is how these usually show up, the warning gives both counts
+pub fn check_fallthrough(commands: &[(usize, Command)]) -> Vec<Lint> {
+    // (line, name, what follows it) of each function that falls through
+    let mut falls_through = Vec::new();
+    let mut function: Option<(usize, &str)> = None;
+    let mut terminated = true;
+    let (mut functions, mut returns) = (0, 0);
+
+    for (line, command) in commands {
+        match command.command_type {
+            CommandType::Function => {
+                let name = command.arg1.as_deref().unwrap_or_default();
+                if let (Some((line, previous)), false) = (function, terminated) {
+                    falls_through.push((line, previous, format!("into `{name}`")));
+                }
+                function = Some((*line, name));
+                functions += 1;
+                terminated = false;
+            }
+            CommandType::Return => {
+                returns += 1;
+                terminated = true;
+            }
+            CommandType::Goto => terminated = true,
+            CommandType::Empty => {}
+            _ => terminated = false,
+        }
+    }
+    if let (Some((line, name)), false) = (function, terminated) {
+        falls_through.push((line, name, "off the end of the file".to_string()));
+    }
+
+    let counts = if returns < functions {
+        format!(" (the file has {functions} functions but {returns} `return`s)")
+    } else {
+        String::new()
+    };
+    falls_through
+        .into_iter()
+        .map(|(line, name, next)| Lint {
+            line,
+            message: format!("function `{name}` can fall {next} without a `return`{counts}"),
+        })
+        .collect()
+}
+
+/// warns about every `call` taking more arguments than were pushed before it. only straight-line
+/// code is checked, using `DepthTracker`, so a call after a label is never warned about
+pub fn check_call_args(commands: &[(usize, Command)]) -> Vec<Lint> {
+    let mut lints = Vec::new();
+    let mut depth = DepthTracker::new();
+    let mut n_locals = 0;
+
+    for (line, command) in commands {
+        match command.command_type {
+            CommandType::Function => n_locals = command.arg2.unwrap_or(0) as i32,
+            CommandType::Call => {
+                let n_args = command.arg2.unwrap_or(0) as i32;
+
+                // the locals are part of the depth but aren't anything a call can take
+                if let Some(pushed) = depth.depth().map(|depth| depth - n_locals) {
+                    if n_args > pushed {
+                        lints.push(Lint {
+                            line: *line,
+                            message: format!(
+                                "`call {}` takes {n_args} arguments but the stack only has {pushed}",
+                                command.arg1.as_deref().unwrap_or_default()
+                            ),
+                        });
+                    }
+                }
+            }
+            _ => {}
+        }
+        depth.step(command);
+    }
+
+    lints
+}
+
+/// the comment that asserts the stack depth at that point, see `check_depth_asserts`
+pub const ASSERT_DEPTH: &str = "@assert-depth";
+
+/// checks every `// @assert-depth n` comment in `source` against the depth `DepthTracker` has
+/// after the commands before it (including one on the same line), returning a lint for each
+/// assertion that doesn't hold or can't be checked because the depth isn't known there
+pub fn check_depth_asserts(source: &str, commands: &[(usize, Command)]) -> Vec<Lint> {
+    let mut lints = Vec::new();
+    let mut depth = DepthTracker::new();
+    let mut commands = commands.iter().peekable();
+
+    for (i, text) in source.lines().enumerate() {
+        let line = i + 1;
+        let Some((_, comment)) = text.split_once("//") else {
+            continue;
+        };
+        let Some(expected) = comment.trim().strip_prefix(ASSERT_DEPTH) else {
+            continue;
+        };
+
+        while let Some((_, command)) = commands.next_if(|(command_line, _)| *command_line <= line) {
+            depth.step(command);
+        }
+
+        let message = match (expected.trim().parse::<i32>(), depth.depth()) {
+            (Err(_), _) => format!("`{}` isn't a valid depth", expected.trim()),
+            (Ok(_), None) => {
+                "the stack depth isn't known here, so it can't be asserted".to_string()
+            }
+            (Ok(expected), Some(actual)) if expected != actual => {
+                format!("expected a stack depth of {expected} but it's {actual}")
+            }
+            _ => continue,
+        };
+        lints.push(Lint { line, message });
+    }
+
+    lints
+}
+
+/// finds every `push constant` in `source` that's only in range as an unsigned number, since it
+/// will be pushed as the negative number with the same bits (40000 is -25536)
+pub fn check_unsigned_constants(source: &str) -> Vec<Lint> {
+    let mut lints = Vec::new();
+
+    for (i, text) in source.lines().enumerate() {
+        let code = text.split("//").next().unwrap_or_default();
+        let ["push", "constant", value] = code.split_whitespace().collect::<Vec<_>>()[..] else {
+            continue;
+        };
+        if crate::parse_index(value).is_ok() {
+            continue;
+        }
+        if let Ok(signed) = crate::parse_constant(value) {
+            lints.push(Lint {
+                line: i + 1,
+                message: format!(
+                    "`push constant {value}` is over 32767, it will be {signed} on the Hack platform"
+                ),
+            });
+        }
+    }
+
+    lints
+}
+
+/// how many times each command appears, keyed by its VM keyword (arithmetic ops by their own name)
+pub fn count_commands(commands: &[(usize, Command)]) -> BTreeMap<String, usize> {
+    let mut counts = BTreeMap::new();
+
+    for (_, command) in commands {
+        if command.command_type != CommandType::Empty {
+            *counts.entry(command.command_type.to_string()).or_insert(0) += 1;
+        }
+    }
+
+    counts
+}
+
+/// who calls whom: every function defined in `commands` maps to the functions it calls and how
+/// many `call`s there are to each. calls made before the first `function` are under
+/// `GLOBAL_SCOPE`
+pub fn call_graph(commands: &[(usize, Command)]) -> BTreeMap<String, BTreeMap<String, usize>> {
+    let mut graph: BTreeMap<String, BTreeMap<String, usize>> = BTreeMap::new();
+    let mut function = GLOBAL_SCOPE.to_string();
+
+    for (_, command) in commands {
+        let name = command.arg1.clone().unwrap_or_default();
+
+        match command.command_type {
+            CommandType::Function => {
+                graph.entry(name.clone()).or_default();
+                function = name;
+            }
+            CommandType::Call => {
+                *graph
+                    .entry(function.clone())
+                    .or_default()
+                    .entry(name)
+                    .or_insert(0) += 1;
+            }
+            _ => {}
+        }
+    }
+
+    graph
+}
+
+/// renders a `call_graph` in graphviz DOT format, each edge labelled with its call count
+pub fn call_graph_dot(graph: &BTreeMap<String, BTreeMap<String, usize>>) -> String {
+    let mut dot = String::from("digraph calls {\n");
+
+    for function in graph.keys() {
+        dot.push_str(&format!("    \"{function}\";\n"));
+    }
+    for (caller, callees) in graph {
+        for (callee, count) in callees {
+            dot.push_str(&format!(
+                "    \"{caller}\" -> \"{callee}\" [label=\"{count}\"];\n"
+            ));
+        }
+    }
+    dot.push_str("}\n");
+
+    dot
+}
+
+/// the `static` indices used by `commands`, each with the raw line of its first `push`/`pop`
+pub fn static_uses(commands: &[(usize, Command)]) -> BTreeMap<i16, usize> {
+    let mut uses = BTreeMap::new();
+
+    for (line, command) in commands {
+        let is_static_access = matches!(command.command_type, CommandType::Push | CommandType::Pop)
+            && command.arg1.as_deref() == Some("static");
+
+        if let (true, Some(index)) = (is_static_access, command.arg2) {
+            uses.entry(index).or_insert(*line);
+        }
+    }
+
+    uses
+}
+
+/// the functions defined by `commands`, each with the raw line of its first `function` command
+pub fn function_definitions(commands: &[(usize, Command)]) -> BTreeMap<String, usize> {
+    let mut definitions = BTreeMap::new();
+
+    for (line, command) in commands {
+        if let (CommandType::Function, Some(name)) = (&command.command_type, &command.arg1) {
+            definitions.entry(name.clone()).or_insert(*line);
+        }
+    }
+
+    definitions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_all;
+
+    #[test]
+    fn max_stack_depth_of_nested_pushes() {
+        let source = "function Foo.bar 2
+push constant 1
+push constant 2
+push constant 3
+add
+add
+push constant 4
+add
+return
+function Foo.baz 0
+push constant 1
+return
+";
+        let commands = parse_all(source).unwrap();
+
+        assert_eq!(
+            max_stack_depth(&commands),
+            [("Foo.bar".to_string(), 5), ("Foo.baz".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn max_stack_depth_takes_the_deepest_branch() {
+        let source = "function Foo.bar 0
+push constant 1
+push constant 1
+if-goto DEEP
+pop temp 0
+label DEEP
+push constant 1
+push constant 1
+return
+";
+        let commands = parse_all(source).unwrap();
+
+        // the jump gets to DEEP with 1 on the stack, falling through gets there with 0
+        assert_eq!(max_stack_depth(&commands), [("Foo.bar".to_string(), 3)]);
+    }
+
+    #[test]
+    fn function_without_return_falls_off_the_end() {
+        let source = "function Foo.bar 0
+push constant 1
+return
+function Foo.baz 0
+push constant 1
+";
+        let lints = check_fallthrough(&parse_all(source).unwrap());
+
+        assert_eq!(
+            lints,
+            [Lint {
+                line: 4,
+                message: "function `Foo.baz` can fall off the end of the file without a \
+                          `return` (the file has 2 functions but 1 `return`s)"
+                    .to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn function_without_return_falls_into_the_next_one() {
+        let source = "function Foo.bar 0
+push constant 1
+function Foo.baz 0
+push constant 2
+return
+";
+        let lints = check_fallthrough(&parse_all(source).unwrap());
+
+        assert_eq!(
+            lints,
+            [Lint {
+                line: 1,
+                message: "function `Foo.bar` can fall into `Foo.baz` without a `return` (the \
+                          file has 2 functions but 1 `return`s)"
+                    .to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn functions_ending_in_return_or_goto_are_fine() {
+        let source = "function Foo.bar 0
+push constant 1
+return
+function Foo.loop 0
+label TOP
+goto TOP
+";
+        assert!(check_fallthrough(&parse_all(source).unwrap()).is_empty());
+    }
+
+    #[test]
+    fn count_commands_by_keyword() {
+        let source = "function Foo.bar 1
+push constant 1
+push local 0
+add
+pop local 0
+label LOOP
+push constant 0
+if-goto LOOP
+call Foo.baz 0
+add
+return
+";
+        let counts = count_commands(&parse_all(source).unwrap());
+        let expected = [
+            ("add", 2),
+            ("call", 1),
+            ("function", 1),
+            ("if-goto", 1),
+            ("label", 1),
+            ("pop", 1),
+            ("push", 3),
+            ("return", 1),
+        ];
+
+        assert_eq!(
+            counts,
+            expected.map(|(keyword, n)| (keyword.to_string(), n)).into()
+        );
+    }
+
+    #[test]
+    fn call_graph_dot_counts_repeated_calls() {
+        let source = "function Main.main 0
+call Math.double 0
+call Math.double 0
+return
+function Math.double 0
+push constant 2
+return
+";
+        let dot = call_graph_dot(&call_graph(&parse_all(source).unwrap()));
+
+        assert_eq!(
+            dot,
+            "digraph calls {
+    \"Main.main\";
+    \"Math.double\";
+    \"Main.main\" -> \"Math.double\" [label=\"2\"];
+}
+"
+        );
+    }
+
+    #[test]
+    fn call_with_more_args_than_pushed_is_warned_about() {
+        let too_few = parse_all("function Main.main 0\npush constant 1\ncall Foo.bar 3\n").unwrap();
+        assert_eq!(
+            check_call_args(&too_few),
+            [Lint {
+                line: 3,
+                message: "`call Foo.bar` takes 3 arguments but the stack only has 1".to_string(),
+            }]
+        );
+
+        let source = "function Main.main 2
+push constant 1
+push constant 2
+push constant 3
+call Foo.bar 3
+label AGAIN
+call Foo.bar 3
+";
+        // the depth after a label isn't known, so that call isn't checked
+        assert!(check_call_args(&parse_all(source).unwrap()).is_empty());
+    }
+
+    #[test]
+    fn depth_asserts_are_checked() {
+        let source = "function Foo.bar 1
+push constant 1 // @assert-depth 2
+push constant 2
+add // @assert-depth 3
+// @assert-depth 2
+return
+";
+        let lints = check_depth_asserts(source, &parse_all(source).unwrap());
+
+        assert_eq!(
+            lints,
+            [Lint {
+                line: 4,
+                message: "expected a stack depth of 3 but it's 2".to_string(),
+            }]
+        );
+    }
+}
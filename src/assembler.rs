@@ -0,0 +1,208 @@
+//! a Hack assembler, turns assembly (hand written or from `CodeWriter`) into machine words
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+};
+
+/// the first RAM address handed out to variables
+const FIRST_VARIABLE: u16 = 16;
+
+/// an error in the assembly, along with the raw line it's on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssembleError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AssembleError {}
+
+/// the symbols every Hack program starts out with
+fn predefined_symbols() -> HashMap<String, u16> {
+    let mut symbols: HashMap<String, u16> = [
+        ("SP", 0),
+        ("LCL", 1),
+        ("ARG", 2),
+        ("THIS", 3),
+        ("THAT", 4),
+        ("SCREEN", 16384),
+        ("KBD", 24576),
+    ]
+    .into_iter()
+    .map(|(symbol, address)| (symbol.to_string(), address))
+    .collect();
+
+    for i in 0..16 {
+        symbols.insert(format!("R{i}"), i);
+    }
+    symbols
+}
+
+/// the `a` bit and 6 `c` bits of a computation
+fn comp_bits(comp: &str) -> Option<u16> {
+    let a_bit = if comp.contains('M') { 1 << 6 } else { 0 };
+    let bits = match comp.replace('M', "A").as_str() {
+        "0" => 0b101010,
+        "1" => 0b111111,
+        "-1" => 0b111010,
+        "D" => 0b001100,
+        "A" => 0b110000,
+        "!D" => 0b001101,
+        "!A" => 0b110001,
+        "-D" => 0b001111,
+        "-A" => 0b110011,
+        "D+1" | "1+D" => 0b011111,
+        "A+1" | "1+A" => 0b110111,
+        "D-1" => 0b001110,
+        "A-1" => 0b110010,
+        "D+A" | "A+D" => 0b000010,
+        "D-A" => 0b010011,
+        "A-D" => 0b000111,
+        "D&A" | "A&D" => 0b000000,
+        "D|A" | "A|D" => 0b010101,
+        _ => return None,
+    };
+
+    Some(a_bit | bits)
+}
+
+fn dest_bits(dest: &str) -> Option<u16> {
+    if !dest.chars().all(|c| "AMD".contains(c)) {
+        return None;
+    }
+
+    let bit = |register, bit| if dest.contains(register) { bit } else { 0 };
+    Some(bit('A', 0b100) | bit('D', 0b010) | bit('M', 0b001))
+}
+
+fn jump_bits(jump: &str) -> Option<u16> {
+    let jumps = ["", "JGT", "JEQ", "JGE", "JLT", "JNE", "JLE", "JMP"];
+    jumps
+        .iter()
+        .position(|x| *x == jump)
+        .map(|bits| bits as u16)
+}
+
+/// encodes a `dest=comp;jump` instruction
+fn assemble_c_instruction(instruction: &str) -> Option<u16> {
+    let (dest, rest) = instruction.split_once('=').unwrap_or(("", instruction));
+    let (comp, jump) = rest.split_once(';').unwrap_or((rest, ""));
+
+    Some(0b111 << 13 | comp_bits(comp)? << 6 | dest_bits(dest)? << 3 | jump_bits(jump)?)
+}
+
+/// assembles `asm` into the words of ROM it describes. labels are resolved in a first pass, so
+/// they can be used before they're declared, and any other unknown `@symbol` is a variable
+/// given the next free RAM address from 16 up
+pub fn assemble(asm: &str) -> Result<Vec<u16>, AssembleError> {
+    assemble_with_symbols(asm).map(|(words, _)| words)
+}
+
+/// `assemble`, also returning the symbol table it ended up with: every label with its ROM
+/// address, every variable with its RAM address and the predefined symbols
+pub fn assemble_with_symbols(
+    asm: &str,
+) -> Result<(Vec<u16>, BTreeMap<String, u16>), AssembleError> {
+    // the instructions without whitespace or comments, each with its raw line
+    let mut instructions = Vec::new();
+    let mut symbols = predefined_symbols();
+
+    for (i, line) in asm.lines().enumerate() {
+        let line_raw = i + 1;
+        let code = line.split("//").next().unwrap_or_default();
+        let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+
+        if code.is_empty() {
+            continue;
+        }
+        if let Some(label) = code.strip_prefix('(') {
+            let label = label.strip_suffix(')').ok_or_else(|| AssembleError {
+                line: line_raw,
+                message: format!("unclosed label `{code}`"),
+            })?;
+            if symbols.contains_key(label) {
+                return Err(AssembleError {
+                    line: line_raw,
+                    message: format!("`{label}` is already defined"),
+                });
+            }
+            symbols.insert(label.to_string(), instructions.len() as u16);
+            continue;
+        }
+
+        instructions.push((line_raw, code));
+    }
+
+    let mut next_variable = FIRST_VARIABLE;
+    let mut words = Vec::with_capacity(instructions.len());
+    for (line, instruction) in instructions {
+        let word = match instruction.strip_prefix('@') {
+            Some(value) if value.starts_with(|c: char| c.is_ascii_digit()) => {
+                value.parse::<u16>().ok().filter(|value| *value < 1 << 15)
+            }
+            Some(symbol) if crate::is_valid_symbol(symbol) => {
+                Some(*symbols.entry(symbol.to_string()).or_insert_with(|| {
+                    next_variable += 1;
+                    next_variable - 1
+                }))
+            }
+            Some(_) => None,
+            None => assemble_c_instruction(&instruction),
+        };
+
+        words.push(word.ok_or_else(|| AssembleError {
+            line,
+            message: format!("invalid instruction `{instruction}`"),
+        })?);
+    }
+
+    Ok((words, symbols.into_iter().collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assembles_instructions_and_labels() {
+        let asm = "@2\nD=A // a comment\n(LOOP)\n@LOOP\n0;JMP\n@SCREEN\nAM=M-1\n";
+
+        assert_eq!(
+            assemble(asm),
+            Ok(vec![
+                2,
+                0b1110_1100_0001_0000,
+                2,
+                0b1110_1010_1000_0111,
+                16384,
+                0b1111_1100_1010_1000,
+            ])
+        );
+    }
+
+    #[test]
+    fn unknown_symbols_are_variables() {
+        let (words, symbols) =
+            assemble_with_symbols("@counter\n@total\n@counter\n@END\n(END)\n").unwrap();
+
+        assert_eq!(words, [16, 17, 16, 4]);
+        assert_eq!(symbols["counter"], 16);
+        assert_eq!(symbols["total"], 17);
+        assert_eq!(symbols["END"], 4);
+    }
+
+    #[test]
+    fn bad_lines_are_reported_with_their_line() {
+        let error = assemble("@1\nD=X\n").unwrap_err();
+        assert_eq!(error.to_string(), "line 2: invalid instruction `D=X`");
+
+        let error = assemble("(LOOP)\n@1\n(LOOP)\n").unwrap_err();
+        assert_eq!(error.to_string(), "line 3: `LOOP` is already defined");
+    }
+}
@@ -1,8 +1,20 @@
-use std::io::{self, BufRead, Error, Seek, SeekFrom, Write};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader, Error, Seek, SeekFrom, Write},
+    num::ParseIntError,
+    ops::RangeInclusive,
+    path::Path,
+};
+
+pub mod analysis;
+pub mod assembler;
+pub mod simulator;
 
 // this might be the first project of mine that i have used this many comments for lol
 
-#[derive(PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum CommandType {
     Arithmetic(String),
     Push,
@@ -13,17 +25,477 @@ pub enum CommandType {
     Function,
     Return,
     Call,
+    /// `push-range segment start count`, pushes `count` consecutive words of a segment
+    PushRange,
+    /// `pop-range segment start count`, the inverse of `push-range`
+    PopRange,
+    /// `push-indexed segment`, replaces the index on top of the stack with segment[index]
+    PushIndexed,
+    /// `pop-indexed segment`, pops the index and then the value to store at segment[index]
+    PopIndexed,
+    /// a `//!name args` comment, handed to the directive handler registered for `name`
+    Directive,
+    /// `debug-print`, pops a value into the debug address, see `CodeWriter::set_debug_address`
+    DebugPrint,
     Empty,
 }
 
+impl CommandType {
+    /// whether this is one of the arithmetic and logic commands (`add`, `eq`, `not`...)
+    pub fn is_arithmetic(&self) -> bool {
+        matches!(self, CommandType::Arithmetic(_))
+    }
+
+    /// whether this is one of the branching commands: `label`, `goto` or `if-goto`
+    pub fn is_branch(&self) -> bool {
+        matches!(
+            self,
+            CommandType::Label | CommandType::Goto | CommandType::If
+        )
+    }
+
+    /// whether this is one of the function commands: `function`, `call` or `return`
+    pub fn is_function(&self) -> bool {
+        matches!(
+            self,
+            CommandType::Function | CommandType::Call | CommandType::Return
+        )
+    }
+}
+
+/// a single VM command with its arguments already split out
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Command {
+    pub command_type: CommandType,
+    pub arg1: Option<String>,
+    pub arg2: Option<i16>,
+    /// only used by the range commands
+    pub arg3: Option<i16>,
+}
+
+impl fmt::Display for CommandType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let keyword = match self {
+            CommandType::Arithmetic(op) => op,
+            CommandType::Push => "push",
+            CommandType::Pop => "pop",
+            CommandType::Label => "label",
+            CommandType::Goto => "goto",
+            CommandType::If => "if-goto",
+            CommandType::Function => "function",
+            CommandType::Return => "return",
+            CommandType::Call => "call",
+            CommandType::PushRange => "push-range",
+            CommandType::PopRange => "pop-range",
+            CommandType::PushIndexed => "push-indexed",
+            CommandType::PopIndexed => "pop-indexed",
+            CommandType::Directive => DIRECTIVE_PREFIX,
+            CommandType::DebugPrint => "debug-print",
+            CommandType::Empty => "",
+        };
+
+        f.write_str(keyword)
+    }
+}
+
+/// formats the command back into VM source
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.command_type)?;
+        match self.command_type {
+            CommandType::Arithmetic(_) => return Ok(()),
+            CommandType::Directive => return f.write_str(self.arg1.as_deref().unwrap_or_default()),
+            _ => {}
+        }
+
+        if let Some(arg1) = &self.arg1 {
+            write!(f, " {arg1}")?;
+        }
+        if let Some(arg2) = self.arg2 {
+            write!(f, " {arg2}")?;
+        }
+        if let Some(arg3) = self.arg3 {
+            write!(f, " {arg3}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Command {
+    /// the net change in stack depth after executing this command, `None` for `function` and
+    /// `return` since they replace the stack frame instead of pushing or popping from it
+    pub fn stack_effect(&self) -> Option<i32> {
+        let n = self.arg2.unwrap_or(0) as i32;
+
+        match &self.command_type {
+            CommandType::Arithmetic(op) if op == "neg" || op == "not" => Some(0),
+            CommandType::Arithmetic(_) => Some(-1),
+            CommandType::Push => Some(1),
+            CommandType::Pop | CommandType::If | CommandType::DebugPrint => Some(-1),
+            CommandType::Call => Some(1 - n),
+            CommandType::PushRange => Some(self.arg3.unwrap_or(0) as i32),
+            CommandType::PopRange => Some(-(self.arg3.unwrap_or(0) as i32)),
+            CommandType::PushIndexed => Some(0),
+            CommandType::PopIndexed => Some(-2),
+            CommandType::Label
+            | CommandType::Goto
+            | CommandType::Directive
+            | CommandType::Empty => Some(0),
+            CommandType::Function | CommandType::Return => None,
+        }
+    }
+}
+
+/// an error translating VM source. everything but `Io` is a problem with the source itself and
+/// comes with the raw line it's on. `CodeWriter` doesn't know which line it's writing, so the
+/// errors it returns have a line of 0 until `at_line` fills it in (`translate` does)
+#[derive(Debug)]
+pub enum VmError {
+    /// the first token of the line isn't a command that exists
+    UnknownCommand {
+        token: String,
+        line: usize,
+    },
+    /// `command` needs an argument at `position` (1-based) that isn't there
+    MissingArgument {
+        command: CommandType,
+        position: usize,
+        line: usize,
+    },
+    /// `command` takes fewer arguments than the line has, `token` is the first one too many
+    UnexpectedArgument {
+        command: CommandType,
+        token: String,
+        line: usize,
+    },
+    /// `name` is where a segment should be but isn't one of `SEGMENTS`
+    UnknownSegment {
+        name: String,
+        line: usize,
+    },
+    /// `value` should be an index but isn't one, `expected` says what it should have been
+    BadIndex {
+        value: String,
+        expected: String,
+        line: usize,
+    },
+    /// the command parsed fine but the code writer rejected it, like `push-range constant 0 2`,
+    /// or the writer was given a setting it can't use
+    Translate {
+        message: String,
+        line: usize,
+    },
+    Io(io::Error),
+}
+
+impl VmError {
+    /// a `Translate` error from the code writer, without its line yet
+    fn rejected(message: String) -> VmError {
+        VmError::Translate { message, line: 0 }
+    }
+
+    /// the raw line the error is on, `None` for `Io` and for errors from `CodeWriter` that
+    /// haven't been given one with `at_line`
+    pub fn line(&self) -> Option<usize> {
+        match self {
+            VmError::UnknownCommand { line, .. }
+            | VmError::MissingArgument { line, .. }
+            | VmError::UnexpectedArgument { line, .. }
+            | VmError::UnknownSegment { line, .. }
+            | VmError::BadIndex { line, .. }
+            | VmError::Translate { line, .. } => Some(*line).filter(|line| *line != 0),
+            VmError::Io(_) => None,
+        }
+    }
+
+    /// puts the error on `raw_line` if it isn't on one yet, for errors from `CodeWriter`
+    pub fn at_line(mut self, raw_line: usize) -> VmError {
+        match &mut self {
+            VmError::UnknownCommand { line, .. }
+            | VmError::MissingArgument { line, .. }
+            | VmError::UnexpectedArgument { line, .. }
+            | VmError::UnknownSegment { line, .. }
+            | VmError::BadIndex { line, .. }
+            | VmError::Translate { line, .. } => {
+                if *line == 0 {
+                    *line = raw_line;
+                }
+            }
+            VmError::Io(_) => {}
+        }
+        self
+    }
+
+    /// what went wrong, without the line
+    pub fn message(&self) -> String {
+        match self {
+            VmError::UnknownCommand { token, .. } => format!("unrecognized command `{token}`"),
+            VmError::MissingArgument {
+                command, position, ..
+            } => format!("`{command}` is missing argument {position}"),
+            VmError::UnexpectedArgument { command, token, .. } => {
+                format!("too many arguments for `{command}`, starting at `{token}`")
+            }
+            VmError::UnknownSegment { name, .. } => format!("unknown segment `{name}`"),
+            VmError::BadIndex {
+                value, expected, ..
+            } => format!("`{value}` is not a valid index, expected {expected}"),
+            VmError::Translate { message, .. } => message.clone(),
+            VmError::Io(e) => e.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line() {
+            Some(line) => write!(f, "line {line}: {}", self.message()),
+            None => f.write_str(&self.message()),
+        }
+    }
+}
+
+impl std::error::Error for VmError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VmError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for VmError {
+    fn from(e: io::Error) -> VmError {
+        VmError::Io(e)
+    }
+}
+
+/// parses all of `source` without generating any assembly, pairing every command with the raw
+/// line it came from. stops at the first line that doesn't parse
+pub fn parse_all(source: &str) -> Result<Vec<(usize, Command)>, VmError> {
+    Parser::new(io::Cursor::new(source.as_bytes())).collect()
+}
+
+/// parses `source` and hands each command to `codegen`, returning the commands with their raw
+/// lines. a command `codegen` rejects is reported as an error on its line
+pub fn translate<G: CodeGen>(
+    source: &str,
+    codegen: &mut G,
+) -> Result<Vec<(usize, Command)>, VmError> {
+    let commands = parse_all(source)?;
+
+    for (line, command) in &commands {
+        codegen
+            .write_command(command)
+            .map_err(|e| e.at_line(*line))?;
+    }
+    Ok(commands)
+}
+
+/// how bad a `Diagnostic` is: errors stop the file from translating, warnings don't
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// a problem found by `diagnose`, the column (1-based like the line) is where the command on
+/// that line starts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub line: usize,
+    pub column: usize,
+    pub severity: Severity,
+    pub message: String,
+}
+
+/// checks `source` as if it was translated into `namespace`, without writing anything, returning
+/// every problem found in line order. unlike `parse_all` it keeps going past bad lines, so an
+/// editor can show all of them at once
+pub fn diagnose(source: &str, namespace: &str) -> Vec<Diagnostic> {
+    let mut parser = Parser::new(io::Cursor::new(source.as_bytes()));
+    let mut writer = CodeWriter::new(io::sink());
+    writer.set_warning_sink(Box::new(io::sink()));
+    writer.set_namespace(namespace.to_string());
+
+    let lines: Vec<&str> = source.lines().collect();
+    let diagnostic = |line: usize, severity, message| {
+        let text = lines.get(line.wrapping_sub(1)).copied().unwrap_or_default();
+        let column = text.len() - text.trim_start().len() + 1;
+        Diagnostic {
+            line,
+            column,
+            severity,
+            message,
+        }
+    };
+
+    let mut diagnostics = Vec::new();
+    let mut commands = Vec::new();
+    loop {
+        match parser.advance() {
+            Ok(true) => {}
+            Ok(false) => break,
+            // a read that fails is the end of what can be checked, but it shouldn't look like
+            // the end of the file. with a `/*` still open it's the comment that ate the rest of
+            // the file, so that's where it's reported
+            Err(e) => {
+                let (line, message) = match parser.block_comment_start {
+                    Some(start) => (start, "this `/*` comment is never closed".to_string()),
+                    None => (parser.line_raw + 1, format!("couldn't read past here: {e}")),
+                };
+                diagnostics.push(diagnostic(line, Severity::Error, message));
+                break;
+            }
+        }
+        let line = parser.line_raw;
+        let result = parser.parse().map_err(|e| e.message()).and_then(|command| {
+            writer.write_command(&command).map_err(|e| e.message())?;
+            Ok(command)
+        });
+
+        match result {
+            Ok(command) => commands.push((line, command)),
+            Err(message) => diagnostics.push(diagnostic(line, Severity::Error, message)),
+        }
+    }
+    let lints = analysis::check_fallthrough(&commands)
+        .into_iter()
+        .chain(analysis::check_call_args(&commands))
+        .chain(analysis::check_unsigned_constants(source));
+    for lint in lints {
+        diagnostics.push(diagnostic(lint.line, Severity::Warning, lint.message));
+    }
+    diagnostics.sort_by_key(|diagnostic| diagnostic.line);
+    diagnostics
+}
+
+/// the indices `segment` canonically has, and how to describe them in an error
+fn segment_bounds(segment: &str) -> (RangeInclusive<i16>, &'static str) {
+    match segment {
+        "pointer" => (0..=1, "0 or 1"),
+        "temp" => (0..=7, "0..=7"),
+        "constant" => (0..=i16::MAX, "0..=32767"),
+        "static" => (0..=STATIC_END - STATIC_BASE, "0..=239"),
+        _ => (0..=i16::MAX, "a non-negative index"),
+    }
+}
+
+/// how many words a `push-range`/`pop-range` starting at `start` can move before it runs off the
+/// end of `segment`, `None` if `start` is out of bounds already
+fn range_capacity(segment: &str, start: i16) -> Option<i32> {
+    let (bounds, _) = segment_bounds(segment);
+    bounds
+        .contains(&start)
+        .then(|| i32::from(*bounds.end()) - i32::from(start) + 1)
+}
+
+/// splits the `0x`/`0b` prefix off of a numeric argument, returning its digits and their radix
+fn split_radix(token: &str) -> (&str, u32) {
+    if let Some(hex) = token.strip_prefix("0x") {
+        (hex, 16)
+    } else if let Some(binary) = token.strip_prefix("0b") {
+        (binary, 2)
+    } else {
+        (token, 10)
+    }
+}
+
+/// parses `token` as an `i16` or `u16` in the radix of its prefix. `from_str_radix` takes a sign
+/// before the digits, which would let `0x-1` through, so a prefixed number with one is handed
+/// over with its prefix still on to be rejected like any other bad digit
+fn parse_radix<T: std::str::FromStr<Err = ParseIntError>>(
+    token: &str,
+    from_str_radix: fn(&str, u32) -> Result<T, ParseIntError>,
+) -> Result<T, ParseIntError> {
+    match split_radix(token) {
+        (digits, 10) => digits.parse(),
+        (digits, radix) if digits.starts_with(['+', '-']) => from_str_radix(token, radix),
+        (digits, radix) => from_str_radix(digits, radix),
+    }
+}
+
+/// parses the numeric argument of a command, which is decimal unless it starts with `0x` (hex) or
+/// `0b` (binary)
+pub fn parse_index(token: &str) -> Result<i16, ParseIntError> {
+    parse_radix(token, i16::from_str_radix)
+}
+
+/// parses the value of a `push constant`, which can also be an unsigned 16-bit number
+/// (32768..=65535). those have the same bit pattern as the negative number they wrap around to,
+/// so 40000 is -25536, see `analysis::check_unsigned_constants`
+pub fn parse_constant(token: &str) -> Result<i16, ParseIntError> {
+    parse_index(token).or_else(|e| {
+        let unsigned = parse_radix(token, u16::from_str_radix);
+        unsigned.map(|value| value as i16).map_err(|_| e)
+    })
+}
+
+/// whether `symbol` can be used as a label or variable by the Hack assembler: letters, digits,
+/// `_`, `.`, `$` and `:`, not starting with a digit
+pub fn is_valid_symbol(symbol: &str) -> bool {
+    let valid_char = |c: char| c.is_ascii_alphanumeric() || "_.$:".contains(c);
+
+    symbol.chars().next().is_some_and(|c| !c.is_ascii_digit()) && symbol.chars().all(valid_char)
+}
+
+/// whether a line of assembly takes up a word of ROM, labels and comments don't
+fn is_instruction(line: &str) -> bool {
+    let line = line.split("//").next().unwrap_or_default().trim();
+    !line.is_empty() && !line.starts_with('(')
+}
+
+/// the tags `get_label` gives the labels it numbers itself, `{tag}.{n}` (or `IF.{op}.{n}`) after
+/// the function's `$`. a VM label of the same form would alias one of them
+const GENERATED_LABEL_TAGS: [&str; 5] = ["ret", "LOCALS", "RANGE", "LOGIC", "IF"];
+
+/// the address the assembler gives the first variable, where statics start without namespacing
+const STATIC_BASE: i16 = 16;
+
+/// the last address statics can use, the stack starts right after it
+const STATIC_END: i16 = 255;
+
+/// functions with more locals than this zero them in a loop instead of one at a time
+const LOCALS_LOOP_THRESHOLD: i16 = 8;
+
+/// the memory segments `push`, `pop` and their range and indexed forms can name
+pub const SEGMENTS: [&str; 8] = [
+    "local", "argument", "this", "that", "constant", "static", "pointer", "temp",
+];
+
+/// what an index that doesn't parse should have looked like, see `parse_index`
+const INDEX_SYNTAX: &str = "a decimal, 0x hex or 0b binary number";
+
+/// what a comment starts with to be a directive instead, see `CodeWriter::register_directive`
+pub const DIRECTIVE_PREFIX: &str = "//!";
+
+/// the last word of the screen, the bottom right 16 pixels, so a value `debug-print`s there
+/// shows up on it
+pub const DEFAULT_DEBUG_ADDRESS: i16 = 24575;
+
 #[derive(Debug)]
-pub struct Parser<W: Seek + BufRead> {
+pub struct Parser<W: BufRead> {
     input: W,
-    has_lines_remaining: bool,
+    keep_empty: bool,
+    case_insensitive: bool,
     cur_line: Option<String>,
-    /// line number not including empty lines or comments
+    /// the line after `cur_line` with its raw line number and byte offset, once something has
+    /// peeked at it
+    peeked: Option<(String, usize, usize)>,
+    /// how many raw lines have been read from `input` so far, peeked ones included
+    lines_read: usize,
+    /// how many bytes have been read from `input` so far, peeked lines included
+    bytes_read: usize,
+    /// where the current line starts in the input, in bytes
+    byte_offset: usize,
+    /// the raw line a `/*` comment that hasn't been closed yet was opened on
+    block_comment_start: Option<usize>,
+    /// the raw line of every line `advance` has counted in `line` so far, in order
+    raw_lines: Vec<usize>,
+    /// line number not including empty lines or comments. on a line `keep_empty` stopped on it's
+    /// still the number of the command before it, since the empty line isn't counted
     pub line: usize,
-    /// actual line number
+    /// actual line number, including empty lines and comments
     pub line_raw: usize,
 }
 
@@ -35,81 +507,208 @@ pub enum LabelType {
     FunctionLabel,
     FunctionCall,
     FunctionRet,
-    CompareTrue,
-    CompareFalse,
+    /// a label for the translator's own control flow (loops and such), unique within the namespace
+    Internal,
+    /// where a comparison continues after the shared true/false tail has pushed its result, named
+    /// after the comparison and numbered within the function (`Foo.bar$IF.eq.0`)
+    CompareRet,
 }
 
-impl<W: Seek + BufRead> Parser<W> {
+impl<W: BufRead> Parser<W> {
     pub fn new(input: W) -> Parser<W> {
         Parser {
             input,
-            has_lines_remaining: false,
+            keep_empty: false,
+            case_insensitive: false,
             cur_line: None,
+            peeked: None,
+            lines_read: 0,
+            bytes_read: 0,
+            byte_offset: 0,
+            block_comment_start: None,
+            raw_lines: Vec::new(),
             line: 0,
             line_raw: 0,
         }
     }
 
-    pub fn has_more_lines(&self) -> bool {
-        self.has_lines_remaining
+    /// whether there's another line for `advance` to move to, reading it ahead like `peek_line`
+    /// if it hasn't been yet, so it works before the first `advance` too. a read that fails
+    /// counts as a line, leaving `advance` to return the error
+    pub fn has_more_lines(&mut self) -> bool {
+        self.peek_line().map_or(true, |line| line.is_some())
+    }
+
+    /// when enabled, `advance` stops on blank and comment-only lines instead of skipping them,
+    /// and `command_type` reports them as `CommandType::Empty`. they don't count towards `line`,
+    /// only `line_raw` moves on to them
+    pub fn set_keep_empty(&mut self, keep_empty: bool) {
+        self.keep_empty = keep_empty;
     }
 
-    pub fn advance(&mut self) -> io::Result<()> {
+    /// when enabled, command keywords are matched whatever their case (`PUSH`, `Add`), by
+    /// lowercasing the first word of each line as it's read. segments and labels are left alone,
+    /// labels being case sensitive on the Hack platform
+    pub fn set_case_insensitive(&mut self, case_insensitive: bool) {
+        self.case_insensitive = case_insensitive;
+    }
+
+    /// reads until a line with something left after stripping comments (or any line when
+    /// `keep_empty` is set), returning it along with its raw line number and the byte offset it
+    /// starts at. `None` at EOF
+    fn read_next(&mut self) -> io::Result<Option<(String, usize, usize)>> {
         let mut next_string = String::new();
         loop {
+            next_string.clear();
+            let mut offset = self.bytes_read;
             let bytes_read = self.input.read_line(&mut next_string)?;
+            self.bytes_read += bytes_read;
             if bytes_read < 1 {
-                self.has_lines_remaining = false;
-                self.cur_line = None;
-
-                return Err(Error::from(io::ErrorKind::UnexpectedEof));
-            } else {
-                next_string = next_string.trim().to_string();
-
-                let comment = next_string.find("//");
-                if comment.is_some() {
-                    let loc = comment.unwrap();
-                    next_string.replace_range(loc.., "");
+                if let Some(start) = self.block_comment_start {
+                    return Err(Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("the `/*` comment on line {start} is never closed"),
+                    ));
                 }
+                return Ok(None);
+            }
 
-                if next_string.is_empty() {
-                    self.line_raw += 1;
-                    continue;
+            // editors on Windows like to start files with a byte order mark, which would
+            // otherwise stick to the first command
+            if self.lines_read == 0 {
+                if let Some(line) = next_string.strip_prefix('\u{feff}') {
+                    next_string = line.to_string();
+                    offset += '\u{feff}'.len_utf8();
                 }
+            }
+            self.lines_read += 1;
+            next_string = self.strip_block_comments(&next_string);
+            next_string = next_string.trim().to_string();
+
+            // directives are the only comments that mean something
+            if next_string.starts_with(DIRECTIVE_PREFIX) {
+                return Ok(Some((next_string, self.lines_read, offset)));
+            }
+            if let Some(loc) = next_string.find("//") {
+                next_string.replace_range(loc.., "");
+                next_string = next_string.trim_end().to_string();
+            }
 
-                self.has_lines_remaining = true;
-                self.cur_line = Some(next_string);
-                self.line += 1;
+            if self.case_insensitive {
+                let keyword_end = next_string.find(char::is_whitespace);
+                let keyword_end = keyword_end.unwrap_or(next_string.len());
+                next_string[..keyword_end].make_ascii_lowercase();
+            }
 
-                return Ok(());
+            if next_string.is_empty() && !self.keep_empty {
+                continue;
             }
+
+            return Ok(Some((next_string, self.lines_read, offset)));
         }
     }
 
-    pub fn reset(&mut self) -> io::Result<()> {
-        self.input.seek(SeekFrom::Start(0))?;
-        self.line = 0;
-        self.line_raw = 0;
-        self.cur_line = None;
+    /// removes the parts of `line` inside `/* */` comments, which can start and end mid-line and
+    /// span several lines. a `//` outside of one comments out the rest of the line, `/*` included
+    fn strip_block_comments(&mut self, line: &str) -> String {
+        let mut code = String::new();
+        let mut rest = line;
+        loop {
+            if self.block_comment_start.is_some() {
+                let Some(end) = rest.find("*/") else {
+                    return code;
+                };
+                self.block_comment_start = None;
+                rest = &rest[end + 2..];
+                continue;
+            }
 
-        Ok(())
+            let Some(start) = rest.find("/*") else {
+                code.push_str(rest);
+                return code;
+            };
+            if rest.find("//").is_some_and(|comment| comment < start) {
+                code.push_str(rest);
+                return code;
+            }
+            // a comment between two tokens still separates them
+            code.push_str(&rest[..start]);
+            code.push(' ');
+            self.block_comment_start = Some(self.lines_read);
+            rest = &rest[start + 2..];
+        }
     }
 
-    pub fn set_file(&mut self, file: W) -> io::Result<()> {
-        self.input = file;
-        self.reset()?;
+    /// moves to the next line, returning whether there was one. `Ok(false)` is the end of the
+    /// input, `Err` is only for reads that fail
+    pub fn advance(&mut self) -> io::Result<bool> {
+        let next = match self.peeked.take() {
+            Some(peeked) => Some(peeked),
+            None => self.read_next()?,
+        };
 
-        Ok(())
+        let Some((next_string, line_raw, byte_offset)) = next else {
+            self.cur_line = None;
+
+            return Ok(false);
+        };
+
+        if !next_string.is_empty() {
+            self.line += 1;
+            self.raw_lines.push(line_raw);
+        }
+        self.line_raw = line_raw;
+        self.byte_offset = byte_offset;
+        self.cur_line = Some(next_string);
+
+        Ok(true)
+    }
+
+    /// the text of the current line as it's parsed: trimmed, with comments stripped. `None`
+    /// before the first `advance` and after the input runs out
+    pub fn current_line(&self) -> Option<&str> {
+        self.cur_line.as_deref()
+    }
+
+    /// where the current line starts in the input, in bytes from the start of it, for mapping
+    /// back to exact positions in the source. this is the start of the raw line, before any
+    /// indentation, except that a byte order mark isn't part of the first line: it still counts
+    /// towards the offset, so a first line after one starts at 3
+    pub fn byte_offset(&self) -> usize {
+        self.byte_offset
+    }
+
+    /// the raw line that `logical` (a value `line` has had) was on, `None` if `advance` hasn't
+    /// got that far yet
+    pub fn raw_line_for(&self, logical: usize) -> Option<usize> {
+        self.raw_lines.get(logical.checked_sub(1)?).copied()
+    }
+
+    /// the line the next `advance` will move to (comments stripped, like `cur_line`) without
+    /// moving to it, `None` if the input has run out. it's only read once, `advance` takes it
+    /// from here instead of reading it again
+    pub fn peek_line(&mut self) -> io::Result<Option<&str>> {
+        if self.peeked.is_none() {
+            self.peeked = self.read_next()?;
+        }
+
+        Ok(self.peeked.as_ref().map(|(line, ..)| line.as_str()))
+    }
+
+    /// the type of the command the next `advance` will move to without moving to it, `None` if
+    /// the input has run out or the next line isn't a command that exists
+    pub fn peek_command_type(&mut self) -> io::Result<Option<CommandType>> {
+        Ok(self.peek_line()?.and_then(Self::try_classify))
     }
 
     fn match_arithmetic(command: String) -> Option<CommandType> {
-        return match command.as_str() {
-            "add" | "sub" | "neg" | "and" | "not" | "eq" | "gt" | "lt" | "or" => {
+        match command.as_str() {
+            "add" | "sub" | "neg" | "and" | "not" | "eq" | "gt" | "lt" | "or" | "land" | "lor" => {
                 Some(CommandType::Arithmetic(command))
             }
 
             _ => None,
-        };
+        }
     }
 
     fn split_command(&self) -> Vec<&str> {
@@ -121,16 +720,41 @@ impl<W: Seek + BufRead> Parser<W> {
         input.split_whitespace().collect()
     }
 
-    pub fn command_type(&self) -> CommandType {
-        let split_line = self.split_command();
-        let command = split_line.first().expect("index zero should exist");
+    /// the type of the current command, erroring with the offending token if it isn't a command
+    /// that exists
+    pub fn command_type(&self) -> Result<CommandType, VmError> {
+        let line = self
+            .cur_line
+            .as_ref()
+            .expect("command_type should not be called before advance");
+
+        Self::try_classify(line).ok_or_else(|| VmError::UnknownCommand {
+            token: line
+                .split_whitespace()
+                .next()
+                .unwrap_or_default()
+                .to_string(),
+            line: self.line_raw,
+        })
+    }
+
+    /// works out the type of an already comment-stripped line, `None` for a command that doesn't
+    /// exist
+    fn try_classify(line: &str) -> Option<CommandType> {
+        if line.starts_with(DIRECTIVE_PREFIX) {
+            return Some(CommandType::Directive);
+        }
+        // only produced for blank/comment lines when `keep_empty` is set
+        let Some(command) = line.split_whitespace().next() else {
+            return Some(CommandType::Empty);
+        };
 
         let result = Parser::<W>::match_arithmetic(command.to_string());
-        if let Some(item) = result {
-            return item;
+        if result.is_some() {
+            return result;
         };
 
-        match *command {
+        let command_type = match command {
             "push" => CommandType::Push,
             "pop" => CommandType::Pop,
             "label" => CommandType::Label,
@@ -139,13 +763,26 @@ impl<W: Seek + BufRead> Parser<W> {
             "call" => CommandType::Call,
             "function" => CommandType::Function,
             "return" => CommandType::Return,
-            _ => todo!(),
-        }
+            "push-range" => CommandType::PushRange,
+            "pop-range" => CommandType::PopRange,
+            "push-indexed" => CommandType::PushIndexed,
+            "pop-indexed" => CommandType::PopIndexed,
+            "debug-print" => CommandType::DebugPrint,
+            _ => return None,
+        };
+
+        Some(command_type)
     }
 
     pub fn arg1(&self) -> Option<String> {
-        let index = match self.command_type() {
+        let index = match self.command_type().ok()? {
             CommandType::Arithmetic(_) => 0,
+            // the whole directive, its name and anything after it
+            CommandType::Directive => {
+                let line = self.cur_line.as_deref().unwrap_or_default();
+                let directive = line.trim_start_matches(DIRECTIVE_PREFIX).trim();
+                return Some(directive.to_string()).filter(|x| !x.is_empty());
+            }
             _ => 1,
         };
 
@@ -155,61 +792,503 @@ impl<W: Seek + BufRead> Parser<W> {
     pub fn arg2(&self) -> Option<String> {
         self.split_command().get(2).map(|x| x.to_string())
     }
+
+    /// `arg2` as a number, parsed the same way `parse` does it, so `push constant` takes the
+    /// unsigned range too. those come back as their 16-bit pattern, without any sign they
+    /// wrapped: `push constant 40000` gives -25536 (see `parse_constant`). `Ok(None)` if there's
+    /// no second argument
+    pub fn arg2_int(&self) -> Result<Option<i16>, VmError> {
+        let Some(value) = self.arg2() else {
+            return Ok(None);
+        };
+        let parsed = match (self.command_type()?, self.arg1().as_deref()) {
+            (CommandType::Push, Some("constant")) => parse_constant(&value),
+            _ => parse_index(&value),
+        };
+
+        match parsed {
+            Ok(index) => Ok(Some(index)),
+            Err(_) => Err(VmError::BadIndex {
+                value,
+                expected: INDEX_SYNTAX.to_string(),
+                line: self.line_raw,
+            }),
+        }
+    }
+
+    pub fn arg3(&self) -> Option<String> {
+        self.split_command().get(3).map(|x| x.to_string())
+    }
+
+    /// the current line as a `Command`, checking that it's a known command and that all of the
+    /// arguments it needs are there and parse. this is the one place a line is checked, so it's
+    /// the one place a malformed line errors
+    pub fn parse(&self) -> Result<Command, VmError> {
+        let (command_type, args) = self.split_args()?;
+
+        let n_args = match command_type {
+            CommandType::Arithmetic(_)
+            | CommandType::Return
+            | CommandType::DebugPrint
+            | CommandType::Empty => 0,
+            CommandType::Label
+            | CommandType::Goto
+            | CommandType::If
+            | CommandType::PushIndexed
+            | CommandType::PopIndexed
+            | CommandType::Directive => 1,
+            CommandType::Push | CommandType::Pop | CommandType::Function | CommandType::Call => 2,
+            CommandType::PushRange | CommandType::PopRange => 3,
+        };
+        if let Some(missing) = args.iter().take(n_args).position(Option::is_none) {
+            return Err(VmError::MissingArgument {
+                command: command_type,
+                position: missing + 1,
+                line: self.line_raw,
+            });
+        }
+        // a directive's argument is the rest of the line, spaces and all
+        if !matches!(command_type, CommandType::Directive) {
+            if let Some(extra) = self.split_command().get(n_args + 1) {
+                return Err(VmError::UnexpectedArgument {
+                    command: command_type,
+                    token: extra.to_string(),
+                    line: self.line_raw,
+                });
+            }
+        }
+        let takes_segment = matches!(
+            command_type,
+            CommandType::Push
+                | CommandType::Pop
+                | CommandType::PushRange
+                | CommandType::PopRange
+                | CommandType::PushIndexed
+                | CommandType::PopIndexed
+        );
+        if let (true, Some(segment)) = (takes_segment, &args[0]) {
+            if !SEGMENTS.contains(&segment.as_str()) {
+                return Err(VmError::UnknownSegment {
+                    name: segment.clone(),
+                    line: self.line_raw,
+                });
+            }
+        }
+        let command = Self::build_command(command_type, args.clone());
+
+        // everything past the first argument is an index
+        let indices = [(&args[1], command.arg2), (&args[2], command.arg3)];
+        for (arg, index) in indices.into_iter().take(n_args.saturating_sub(1)) {
+            let Some(arg) = arg else {
+                continue;
+            };
+            let expected = match index {
+                None => INDEX_SYNTAX.to_string(),
+                // the unsigned constants above 32767 parse negative, but aren't written that way
+                Some(_) if arg.starts_with('-') => "a non-negative index".to_string(),
+                Some(_) => continue,
+            };
+            return Err(VmError::BadIndex {
+                value: arg.clone(),
+                expected,
+                line: self.line_raw,
+            });
+        }
+
+        // `push`/`pop` are the ones where an index past the segment's end is a mistake
+        if let (CommandType::Push | CommandType::Pop, Some(segment), Some(index)) =
+            (&command.command_type, &command.arg1, command.arg2)
+        {
+            let (bounds, expected) = segment_bounds(segment);
+            if segment != "constant" && !bounds.contains(&index) {
+                return Err(VmError::BadIndex {
+                    value: index.to_string(),
+                    expected: format!("{expected} for segment '{segment}'"),
+                    line: self.line_raw,
+                });
+            }
+        }
+
+        // and for the ranges, neither end can be past it
+        if let (CommandType::PushRange | CommandType::PopRange, Some(segment), Some(start)) =
+            (&command.command_type, &command.arg1, command.arg2)
+        {
+            let (_, expected) = segment_bounds(segment);
+            let error = match (range_capacity(segment, start), command.arg3) {
+                (None, _) => Some((start, format!("{expected} for segment '{segment}'"))),
+                (Some(capacity), Some(count)) if i32::from(count) > capacity => Some((
+                    count,
+                    format!(
+                        "a count of at most {capacity} from index {start} of segment '{segment}'"
+                    ),
+                )),
+                _ => None,
+            };
+            if let Some((value, expected)) = error {
+                return Err(VmError::BadIndex {
+                    value: value.to_string(),
+                    expected,
+                    line: self.line_raw,
+                });
+            }
+        }
+
+        Ok(command)
+    }
+
+    /// parses everything that's left like iterating does, but carries on past lines that don't
+    /// parse so all of their errors are found at once, unlike the free `parse_all` which stops at
+    /// the first. a read that fails ends it
+    pub fn parse_all_recovering(&mut self) -> (Vec<(usize, Command)>, Vec<VmError>) {
+        let mut commands = Vec::new();
+        let mut errors = Vec::new();
+        for result in self.by_ref() {
+            match result {
+                Ok(command) => commands.push(command),
+                Err(e @ VmError::Io(_)) => {
+                    errors.push(e);
+                    break;
+                }
+                Err(e) => errors.push(e),
+            }
+        }
+
+        (commands, errors)
+    }
+
+    /// the current line as a `Command`, arguments that are missing or fail to parse are `None`.
+    /// errors like `command_type` for a command that doesn't exist
+    pub fn command(&self) -> Result<Command, VmError> {
+        let (command_type, args) = self.split_args()?;
+        Ok(Self::build_command(command_type, args))
+    }
+
+    /// the type of the current command and the arguments it takes as written, splitting the
+    /// line only once
+    fn split_args(&self) -> Result<(CommandType, [Option<String>; 3]), VmError> {
+        let command_type = self.command_type()?;
+        let tokens = self.split_command();
+        let token = |i: usize| tokens.get(i).map(|x| x.to_string());
+
+        let args = match command_type {
+            CommandType::Return | CommandType::DebugPrint | CommandType::Empty => {
+                [None, None, None]
+            }
+            CommandType::Arithmetic(_) => [token(0), None, None],
+            CommandType::Directive => [self.arg1(), None, None],
+            _ => [token(1), token(2), token(3)],
+        };
+        Ok((command_type, args))
+    }
+
+    /// a `Command` from the arguments `split_args` found, the ones that don't parse are `None`
+    fn build_command(command_type: CommandType, args: [Option<String>; 3]) -> Command {
+        let [arg1, arg2, arg3] = args;
+        let arg2 = arg2.and_then(|x| match (&command_type, arg1.as_deref()) {
+            (CommandType::Push, Some("constant")) => parse_constant(&x).ok(),
+            _ => parse_index(&x).ok(),
+        });
+        let arg3 = arg3.and_then(|x| parse_index(&x).ok());
+
+        Command {
+            command_type,
+            arg1,
+            arg2,
+            arg3,
+        }
+    }
 }
 
-#[derive(Debug)]
-pub struct CodeWriter<W: Write + Seek> {
+impl Parser<BufReader<File>> {
+    /// a parser over the file at `path`, opened and buffered
+    pub fn from_path(path: impl AsRef<Path>) -> io::Result<Parser<BufReader<File>>> {
+        Ok(Parser::new(BufReader::new(File::open(path)?)))
+    }
+}
+
+/// going back to the start needs an input that can seek, the rest of the parser only reads forward
+/// so it works over pipes and stdin too
+impl<W: Seek + BufRead> Parser<W> {
+    /// rewinds to the start of the input, leaving the parser as it was when it was created
+    /// (apart from settings like `set_keep_empty`), so the same file can be parsed in several
+    /// passes, even after the first one ran to the end
+    pub fn reset(&mut self) -> io::Result<()> {
+        self.input.seek(SeekFrom::Start(0))?;
+        self.line = 0;
+        self.line_raw = 0;
+        self.lines_read = 0;
+        self.bytes_read = 0;
+        self.byte_offset = 0;
+        self.block_comment_start = None;
+        self.raw_lines.clear();
+        self.cur_line = None;
+        self.peeked = None;
+
+        Ok(())
+    }
+
+    pub fn set_file(&mut self, file: W) -> io::Result<()> {
+        self.input = file;
+        self.reset()?;
+
+        Ok(())
+    }
+}
+
+/// yields each command that's left along with its raw line, like `advance` followed by `command`
+/// with the argument checks of `parse`. ends at the end of the input, a failed read is an
+/// `Err` like a line that doesn't parse
+impl<W: BufRead> Iterator for Parser<W> {
+    type Item = Result<(usize, Command), VmError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.advance() {
+            Ok(true) => Some(self.parse().map(|command| (self.line_raw, command))),
+            Ok(false) => None,
+            Err(e) => Some(Err(VmError::Io(e))),
+        }
+    }
+}
+
+/// handles a directive, given everything after its name, returning the assembly to write for it
+pub type DirectiveHandler = Box<dyn FnMut(&str) -> Result<String, VmError> + Send>;
+
+pub struct CodeWriter<W: Write> {
     out_stream: W,
+    /// where warnings about questionable (but translatable) VM code end up, stderr by default
+    warnings: Box<dyn Write + Send>,
     namespace: String,
+    label_prefix: String,
+    /// the function being translated, empty outside of any function
     cur_func: String,
+    /// instructions emitted for each function, see `per_function_sizes`
+    function_sizes: BTreeMap<String, usize>,
+    /// where every label counter starts, see `with_counter_base`
+    counter_base: usize,
     call_count: usize,
     compare_count: usize,
+    uses_compare_tail: bool,
+    /// whether a `gt`/`lt` has jumped to the signed difference part of the tail
+    uses_signed_compare: bool,
+    instruction_count: usize,
+    internal_count: usize,
+    emulator_builtins: bool,
+    zero_locals: bool,
+    entry_point: String,
+    interleave: bool,
+    strict_indices: bool,
+    inline_compares: bool,
+    reference_compat: bool,
+    resolve_comments: bool,
+    static_namespace: bool,
+    /// the R-registers used as scratch, see `set_scratch_registers`
+    frame_register: u8,
+    ret_register: u8,
+    extra_register: u8,
+    /// handlers for `//!` directives by name, see `register_directive`
+    directives: HashMap<String, DirectiveHandler>,
+    /// how many times each name has been given to `write_checkpoint`
+    checkpoints: HashMap<String, usize>,
+    /// where `debug-print` stores the values it pops
+    debug_address: i16,
+}
+
+impl<W: Write + fmt::Debug> fmt::Debug for CodeWriter<W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CodeWriter")
+            .field("out_stream", &self.out_stream)
+            .field("namespace", &self.namespace)
+            .field("label_prefix", &self.label_prefix)
+            .field("cur_func", &self.cur_func)
+            .field("counter_base", &self.counter_base)
+            .field("call_count", &self.call_count)
+            .field("compare_count", &self.compare_count)
+            .field("internal_count", &self.internal_count)
+            .field("emulator_builtins", &self.emulator_builtins)
+            .field("zero_locals", &self.zero_locals)
+            .field("entry_point", &self.entry_point)
+            .field("interleave", &self.interleave)
+            .field("strict_indices", &self.strict_indices)
+            .field("inline_compares", &self.inline_compares)
+            .field("reference_compat", &self.reference_compat)
+            .field("resolve_comments", &self.resolve_comments)
+            .field("static_namespace", &self.static_namespace)
+            .field("frame_register", &self.frame_register)
+            .field("ret_register", &self.ret_register)
+            .field("extra_register", &self.extra_register)
+            .field("directives", &self.directives.keys().collect::<Vec<_>>())
+            .field("checkpoints", &self.checkpoints)
+            .field("debug_address", &self.debug_address)
+            .finish_non_exhaustive()
+    }
 }
 
-impl<W: Write + Seek> CodeWriter<W> {
+impl<W: Write> CodeWriter<W> {
     pub fn new(out_stream: W) -> CodeWriter<W> {
-        CodeWriter {
+        let mut writer = CodeWriter {
             out_stream,
+            warnings: Box::new(io::stderr()),
             namespace: String::new(),
+            label_prefix: String::new(),
             cur_func: String::new(),
+            function_sizes: BTreeMap::new(),
+            counter_base: 0,
             call_count: 0,
             compare_count: 0,
-        }
+            uses_compare_tail: false,
+            uses_signed_compare: false,
+            instruction_count: 0,
+            internal_count: 0,
+            emulator_builtins: false,
+            zero_locals: true,
+            entry_point: "Sys.init".to_string(),
+            interleave: false,
+            strict_indices: false,
+            inline_compares: false,
+            reference_compat: false,
+            resolve_comments: false,
+            static_namespace: true,
+            frame_register: 13,
+            ret_register: 14,
+            extra_register: 15,
+            directives: HashMap::new(),
+            checkpoints: HashMap::new(),
+            debug_address: DEFAULT_DEBUG_ADDRESS,
+        };
+
+        // `//!label NAME` writes `(NAME)` as it is, without the function's scope
+        writer.register_directive("label", |name| {
+            if !is_valid_symbol(name) {
+                return Err(VmError::rejected(format!("`{name}` isn't a valid label")));
+            }
+            Ok(format!("({name})\n"))
+        });
+        writer
+    }
+
+    /// a writer whose numbered labels all count up from `base` instead of 0. writers translating
+    /// separately (on other threads, say) can be given bases far enough apart that their output
+    /// can be joined with `append` without any label being generated twice
+    pub fn with_counter_base(out_stream: W, base: usize) -> CodeWriter<W> {
+        let mut writer = CodeWriter::new(out_stream);
+        writer.counter_base = base;
+        writer.call_count = base;
+        writer.compare_count = base;
+        writer.internal_count = base;
+        writer
     }
 
+    /// starts translating a new file, anything before its first `function` is global code.
+    /// every label is scoped to a function or the file, so the counters start over for each file,
+    /// which keeps a file's output the same whatever was translated before it
     pub fn set_namespace(&mut self, new_namespace: String) {
         self.namespace = new_namespace;
+        self.cur_func.clear();
+        self.call_count = self.counter_base;
+        self.compare_count = self.counter_base;
+        self.internal_count = self.counter_base;
     }
 
     pub fn get_namespace(&self) -> &String {
         &self.namespace
     }
 
-    fn map_vreg(register: &String) -> String {
-        match register.as_str() {
-            "local" => "LCL".to_string(),
-            "argument" => "ARG".to_string(),
-            "this" => "THIS".to_string(),
-            "that" => "THAT".to_string(),
-            _ => register.to_owned(),
-        }
+    /// the out_stream, for getting at what's been written so far when it's in memory
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.out_stream
     }
 
-    fn decrement_sp() -> String {
-        "@SP\n AM=M-1\n".to_string()
+    /// redirects warnings to `sink` instead of stderr
+    pub fn set_warning_sink(&mut self, sink: Box<dyn Write + Send>) {
+        self.warnings = sink;
     }
 
-    fn pop_d() -> String {
-        Self::decrement_sp() + " D=M // pop D\n"
+    /// writes a warning to the warning sink
+    fn warn(&mut self, message: &str) -> io::Result<()> {
+        self.warnings.write_all(message.as_bytes())
     }
 
-    /// does not use the D register
-    fn pop_a() -> String {
-        Self::decrement_sp() + " A=M // pop A\n"
+    /// targets the official emulators, which provide the OS functions themselves: the bootstrap
+    /// becomes the spec's `call Sys.init 0` so the stack frame looks the way they expect. calls to
+    /// the OS are never rewritten, with or without this, so they always reach the built-ins
+    pub fn set_emulator_builtins(&mut self, emulator_builtins: bool) {
+        self.emulator_builtins = emulator_builtins;
     }
 
-    fn push_d() -> String {
-        "@SP\n A=M\n M=D\n @SP\n M=M+1 // push D\n".to_string()
+    /// the function `write_init` starts the program at, `Sys.init` by default
+    pub fn set_entry_point(&mut self, function_name: &str) -> Result<(), VmError> {
+        if !is_valid_symbol(function_name) {
+            return Err(VmError::rejected(format!(
+                "'{function_name}' is not a valid entry point name"
+            )));
+        }
+
+        self.entry_point = function_name.to_string();
+        Ok(())
+    }
+
+    /// which of R13-R15 the generated code uses as scratch, 13/14/15 by default. `frame` holds the
+    /// frame in `write_return` and the address being written by a pop, `ret` holds return
+    /// addresses for `write_return` and comparisons, and `extra` is the counter for range
+    /// commands. they have to be distinct since some of them are live at the same time
+    pub fn set_scratch_registers(&mut self, frame: u8, ret: u8, extra: u8) -> Result<(), VmError> {
+        let registers = [frame, ret, extra];
+        if registers.iter().any(|r| !(13..=15).contains(r))
+            || frame == ret
+            || frame == extra
+            || ret == extra
+        {
+            return Err(VmError::rejected(format!(
+                "scratch registers must be distinct and in R13-R15, got {registers:?}"
+            )));
+        }
+
+        self.frame_register = frame;
+        self.ret_register = ret;
+        self.extra_register = extra;
+        Ok(())
+    }
+
+    /// when disabled, `write_function` only moves SP past the locals instead of zeroing them.
+    ///
+    /// this is unsafe: the locals start out holding whatever the stack last had there, so it's
+    /// only correct for code that is known to set every local before reading it
+    pub fn set_zero_locals(&mut self, zero_locals: bool) {
+        self.zero_locals = zero_locals;
+    }
+
+    /// prefixes every label the translator generates for itself (comparison branches, return
+    /// addresses and the end loop) so the output of separately translated programs can be
+    /// concatenated without collisions. labels and functions from the VM code are unaffected.
+    pub fn set_label_prefix(&mut self, prefix: &str) {
+        self.label_prefix = prefix.to_string();
+    }
+
+    fn map_vreg(register: &String) -> String {
+        match register.as_str() {
+            "local" => "LCL".to_string(),
+            "argument" => "ARG".to_string(),
+            "this" => "THIS".to_string(),
+            "that" => "THAT".to_string(),
+            _ => register.to_owned(),
+        }
+    }
+
+    fn decrement_sp() -> String {
+        "@SP\n AM=M-1\n".to_string()
+    }
+
+    fn pop_d() -> String {
+        Self::decrement_sp() + " D=M // pop D\n"
+    }
+
+    /// does not use the D register
+    fn pop_a() -> String {
+        Self::decrement_sp() + " A=M // pop A\n"
+    }
+
+    fn push_d() -> String {
+        "@SP\n A=M\n M=D\n @SP\n M=M+1 // push D\n".to_string()
     }
 
     /// the address to M must be loaded in A first
@@ -218,9 +1297,20 @@ impl<W: Write + Seek> CodeWriter<W> {
         "D=M\n ".to_owned() + &Self::push_d()
     }
 
-    fn push_locals(n_vars: i16) -> String {
+    /// zeroes n_vars words from SP up and moves SP past them. small counts are unrolled, anything
+    /// above `LOCALS_LOOP_THRESHOLD` uses a loop so the code size stays constant
+    fn push_locals(&mut self, n_vars: i16) -> Result<String, VmError> {
         if n_vars == 0 {
-            return String::new();
+            return Ok(String::new());
+        }
+
+        if n_vars > LOCALS_LOOP_THRESHOLD {
+            let label = self.get_label(LabelType::Internal, Some(&"LOCALS".to_string()))?;
+
+            // D counts down from n_vars and doubles as the offset from SP of the next local
+            return Ok(format!(
+                "@{n_vars}\nD=A\n({label})\nD=D-1\n@SP\nA=D+M\nM=0\n@{label}\nD;JGT\n"
+            ) + &format!("@{n_vars}\nD=A\n@SP\nM=D+M\n"));
         }
 
         let mut result = format!("@{n_vars}\nD=A\n@SP\nA=M\n");
@@ -232,12 +1322,17 @@ impl<W: Write + Seek> CodeWriter<W> {
         }
         result.push_str("@SP\nM=D+M\n");
 
-        result
+        Ok(result)
     }
 
-    /// loads val into D
+    /// loads val into D. an A-instruction can only hold 0..=32767, so negative values are loaded
+    /// as their magnitude and negated, and -32768 (which has no positive counterpart) as !32767
     fn load_const(val: i16) -> String {
-        format!("@{val}\n D=A\n")
+        match val {
+            i16::MIN => format!("@{}\n D=!A\n", i16::MAX),
+            -32767..=-1 => format!("@{}\n D=-A\n", -val),
+            _ => format!("@{val}\n D=A\n"),
+        }
     }
 
     /// pushes val onto the stack
@@ -249,54 +1344,132 @@ impl<W: Write + Seek> CodeWriter<W> {
         format!("@{label_name}\n") + &Self::push_m()
     }
 
+    /// errors if `index` is outside of the canonical bounds of `segment`: 0 or 1 for `pointer`,
+    /// 0..=7 for `temp`, 0..=239 for `static`, 0..=32767 for `constant` and never negative for the
+    /// rest
+    fn check_index(segment: &str, index: i16) -> Result<(), VmError> {
+        let (bounds, expected) = segment_bounds(segment);
+        if bounds.contains(&index) {
+            return Ok(());
+        }
+
+        // a constant above 32767 was wrapped to its 16-bit pattern when it was parsed (see
+        // `parse_constant`), so it's shown unsigned again, the way it was written
+        let value = match segment {
+            "constant" => (index as u16).to_string(),
+            _ => index.to_string(),
+        };
+        Err(VmError::rejected(format!(
+            "index {value} is out of bounds for segment '{segment}', expected {expected}"
+        )))
+    }
+
+    /// errors if `segment` isn't one of `SEGMENTS`, which the parser checks but a library caller
+    /// might not have
+    fn check_segment(segment: &str) -> Result<(), VmError> {
+        if SEGMENTS.contains(&segment) {
+            return Ok(());
+        }
+
+        Err(VmError::UnknownSegment {
+            name: segment.to_string(),
+            line: 0,
+        })
+    }
+
+    /// the error for a `write_*` method given a command it doesn't write, `expected` being the
+    /// ones it does
+    fn wrong_command(command: &CommandType, expected: &str) -> VmError {
+        VmError::rejected(format!("`{command}` can't be written as {expected}"))
+    }
+
     /// sets the A register to the location that THIS or THAT points to
     fn load_pointer_segment(index: i16) -> String {
         let segment = if index == 0 { "THIS" } else { "THAT" };
 
         format!("@{segment}\n")
     }
-    /// returns an assembly label formatted for use in the VM
-    fn get_label(&mut self, label_type: LabelType, label_name: Option<&String>) -> String {
+    /// errors if `name` could alias a generated label, which all use `$` to separate themselves
+    /// from the function they're in. `$` isn't part of the VM's symbol syntax, so only a
+    /// hand written program would ever have one
+    fn check_user_symbol(name: &str) -> Result<(), VmError> {
+        if !name.contains('$') {
+            return Ok(());
+        }
+
+        Err(VmError::rejected(format!(
+            "'{name}' isn't a valid name, it could clash with a generated label"
+        )))
+    }
+
+    /// returns an assembly label formatted for use in the VM, erroring if the result isn't a legal
+    /// symbol or a user label would alias one the translator generates
+    fn get_label(
+        &mut self,
+        label_type: LabelType,
+        label_name: Option<&String>,
+    ) -> Result<String, VmError> {
         let label_name = if let Some(label) = label_name {
             label
         } else {
             &String::new()
         };
+        if let LabelType::FunctionLabel | LabelType::FunctionRet = label_type {
+            Self::check_user_symbol(label_name)?;
+        }
+        if let (LabelType::FunctionLabel, Some((tag, rest))) =
+            (&label_type, label_name.split_once('.'))
+        {
+            let is_number =
+                |part: &str| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit());
+            if GENERATED_LABEL_TAGS.contains(&tag) && rest.split('.').any(is_number) {
+                return Err(VmError::rejected(format!(
+                    "label '{label_name}' would clash with a generated label"
+                )));
+            }
+        }
         let namespace = &self.namespace;
-        let function_name = &self.cur_func;
+        // function names already start with their file's namespace (`Foo.bar`), code outside of
+        // any function is scoped to the file instead
+        let scope = match self.cur_func.as_str() {
+            "" => format!("{namespace}."),
+            function_name => function_name.to_string(),
+        };
+        let prefix = &self.label_prefix;
 
-        match label_type {
+        let label = match label_type {
             LabelType::Static => format!("{namespace}.{label_name}"),
-            LabelType::FunctionCall => format!("{namespace}.{function_name}"),
+            LabelType::FunctionCall => scope,
             LabelType::FunctionRet => {
                 let call_count = self.call_count;
                 self.call_count += 1;
-                format!("{namespace}.{function_name}$ret.{call_count}")
+                format!("{prefix}{scope}$ret.{call_count}")
             }
             LabelType::FunctionLabel => {
-                format!("{namespace}.{function_name}${label_name}")
+                format!("{scope}${label_name}")
+            }
+            LabelType::Internal => {
+                let internal_count = self.internal_count;
+                self.internal_count += 1;
+                format!("{prefix}{scope}${label_name}.{internal_count}")
             }
 
             // this came about due to me desperately trying to figure out what was wrong with the
             // code and why the compare test wouldn't work, onlt to find out it's the test checking
             // the wrong RAM locations and my implementation works just fine 💀
-            LabelType::CompareFalse => {
-                let call_count = self.call_count;
-                let compare_count = self.compare_count;
-                self.compare_count += 1;
-                format!(
-                    "{namespace}.{function_name}${label_name}$F_{compare_count}_func{call_count}"
-                )
-            }
-            LabelType::CompareTrue => {
-                let call_count = self.call_count;
+            LabelType::CompareRet => {
                 let compare_count = self.compare_count;
                 self.compare_count += 1;
-                format!(
-                    "{namespace}.{function_name}${label_name}$T_{compare_count}_func{call_count}"
-                )
+                format!("{prefix}{scope}$IF.{label_name}.{compare_count}")
             }
+        };
+
+        if !is_valid_symbol(&label) {
+            return Err(VmError::rejected(format!(
+                "'{label}' isn't a symbol the assembler accepts"
+            )));
         }
+        Ok(label)
     }
 
     /// sets target_reg to the base address of segment + index
@@ -305,10 +1478,42 @@ impl<W: Write + Seek> CodeWriter<W> {
         format!("@{index}\n D=A\n @{segment}\n A=M\n {target_reg}=D+A\n")
     }
     /// calculates the label for the static value at index and loads it into A
-    fn load_static_address(&mut self, index: i16) -> String {
-        let static_var = self.get_label(LabelType::Static, Some(&index.to_string()));
+    fn load_static_address(&mut self, index: i16) -> Result<String, VmError> {
+        if !self.static_namespace {
+            let address = Self::static_address(index).ok_or_else(|| {
+                VmError::rejected(format!(
+                    "static {index} is past the end of the static segment at {STATIC_END}"
+                ))
+            })?;
+            return Ok(format!("@{address}\n"));
+        }
+        let static_var = self.get_label(LabelType::Static, Some(&index.to_string()))?;
+
+        Ok(format!("@{static_var}\n"))
+    }
+
+    /// the address of static `index` without namespacing, `None` if it's outside of 16..=255
+    fn static_address(index: i16) -> Option<i16> {
+        STATIC_BASE
+            .checked_add(index)
+            .filter(|address| (STATIC_BASE..=STATIC_END).contains(address))
+    }
 
-        format!("@{static_var}\n")
+    /// where segment[index] lives, for `set_resolve_comments`. `None` for constants
+    fn resolve_target(&self, segment: &str, index: i16) -> Option<String> {
+        match segment {
+            "local" | "argument" | "this" | "that" => {
+                Some(format!("{}+{index}", Self::map_vreg(&segment.to_string())))
+            }
+            "temp" => Some(format!("R{}", 5 + index)),
+            "pointer" if index == 0 => Some("THIS".to_string()),
+            "pointer" => Some("THAT".to_string()),
+            "static" if !self.static_namespace => {
+                Self::static_address(index).map(|a| a.to_string())
+            }
+            "static" => Some(format!("{}.{index}", self.namespace)),
+            _ => None,
+        }
     }
 
     /// writes a push or pop VM command to out_stream
@@ -317,29 +1522,29 @@ impl<W: Write + Seek> CodeWriter<W> {
         command: CommandType,
         segment: String,
         index: i16,
-    ) -> io::Result<()> {
-        let push_comment = format!("// push {segment} {index}\n\n");
-        let pop_comment = format!("// pop {segment} {index}\n\n");
-
+    ) -> Result<(), VmError> {
+        Self::check_segment(&segment)?;
+        // anything past temp 7 is in the general purpose registers the translator uses itself, and
+        // pointer only has THIS and THAT
+        if self.strict_indices || segment == "temp" || segment == "pointer" {
+            Self::check_index(&segment, index)?;
+        }
+        let resolved = match self.resolve_target(&segment, index) {
+            Some(target) if self.resolve_comments => format!(" -> {target}"),
+            _ => String::new(),
+        };
+        let push_comment = format!("// push {segment} {index}{resolved}\n\n");
+        let pop_comment = format!("// pop {segment} {index}{resolved}\n\n");
         let result = match command {
             CommandType::Push if &segment == "pointer" => {
                 Self::load_pointer_segment(index) + "D=M\n " + &Self::push_d() + &push_comment
             }
             CommandType::Push if &segment == "static" => {
-                self.load_static_address(index) + "D=M\n" + &Self::push_d() + &push_comment
+                self.load_static_address(index)? + "D=M\n" + &Self::push_d() + &push_comment
             }
             CommandType::Push if &segment == "constant" => Self::push_const(index) + &push_comment,
             CommandType::Push if &segment == "temp" => {
-                let mut error_comment = "";
-                if index > 7 {
-                    error_comment = "// Warning: access to segment 'temp' above index 7 will cause overflow related errors\n";
-                    eprint!("{}", error_comment);
-                }
-                Self::load_const(index)
-                    + "@5\n A=D+A\n D=M\n"
-                    + &Self::push_d()
-                    + &push_comment
-                    + error_comment
+                Self::load_const(index) + "@5\n A=D+A\n D=M\n" + &Self::push_d() + &push_comment
             }
             CommandType::Push => {
                 Self::load_vreg_address(&segment, index, 'A')
@@ -351,40 +1556,155 @@ impl<W: Write + Seek> CodeWriter<W> {
                 Self::pop_d() + &Self::load_pointer_segment(index) + "M=D\n" + &pop_comment
             }
             CommandType::Pop if &segment == "static" => {
-                Self::pop_d() + &self.load_static_address(index) + "M=D\n" + &pop_comment
+                Self::pop_d() + &self.load_static_address(index)? + "M=D\n" + &pop_comment
             }
             CommandType::Pop if &segment == "constant" => {
                 Self::pop_d() + &format!("@{index}\n M=D\n") + &pop_comment
             }
             CommandType::Pop if &segment == "temp" => {
-                let mut error_comment = "";
-                if index > 7 {
-                    error_comment = "// Warning: access to segment 'temp' above index 7 will cause overflow related errors\n";
-                    eprint!("{}", error_comment);
-                }
-
                 Self::load_const(index)
-                    + "@5\n D=D+A\n @R13\n M=D\n"
+                    + "@5\n D=D+A\n "
+                    + &Self::store_temp_var(self.frame_register)
                     + &Self::pop_d()
-                    + "@R13\n A=M\n M=D\n"
+                    + &Self::get_temp_var(self.frame_register, "A")
+                    + "M=D\n"
                     + &pop_comment
-                    + error_comment
             }
             CommandType::Pop => {
                 Self::load_vreg_address(&segment, index, 'D')
-                    + "@R13\n M=D\n"
+                    + &Self::store_temp_var(self.frame_register)
                     + &Self::pop_d()
-                    + "@R13\n A=M\n M=D\n"
+                    + &Self::get_temp_var(self.frame_register, "A")
+                    + "M=D\n"
                     + &pop_comment
             }
-            _ => return Ok(()),
+            _ => return Err(Self::wrong_command(&command, "a push or pop")),
         };
 
-        self.out_stream.write_all(result.as_bytes())?;
+        self.emit(&result)?;
         Ok(())
     }
+    /// sets D to the address of segment[offset], `None` for segments that aren't a contiguous
+    /// block of memory (constant, static). the offset is added at runtime so there's nothing to
+    /// overflow here
+    fn load_segment_address(segment: &str, offset: i16) -> Option<String> {
+        Some(format!("@{offset}\nD=A\n") + &Self::add_segment_base(segment)?)
+    }
+
+    /// adds the base address of a segment to D, `None` for segments that aren't a contiguous
+    /// block of memory (constant, static)
+    fn add_segment_base(segment: &str) -> Option<String> {
+        match segment {
+            "local" | "argument" | "this" | "that" => {
+                let segment = Self::map_vreg(&segment.to_string());
+                Some(format!("@{segment}\nD=D+M\n"))
+            }
+            "temp" => Some("@5\nD=D+A\n".to_string()),
+            "pointer" => Some("@3\nD=D+A\n".to_string()),
+            _ => None,
+        }
+    }
+
+    /// writes a `push-indexed` or `pop-indexed` command to the out_stream. the index is popped
+    /// off the top of the stack at runtime, so arrays can be walked without going through
+    /// `pointer 1` and `that`. `push-indexed` pushes segment[index], `pop-indexed` then pops the
+    /// value underneath the index into segment[index]
+    pub fn write_push_pop_indexed(
+        &mut self,
+        command: CommandType,
+        segment: String,
+    ) -> Result<(), VmError> {
+        Self::check_segment(&segment)?;
+        let comment = format!("// {command} {segment}\n\n");
+        let add_base = Self::add_segment_base(&segment).ok_or_else(|| {
+            VmError::rejected(format!("segment '{segment}' can't be used with {command}"))
+        })?;
+
+        let result = match command {
+            // the index is replaced in place, so the stack pointer never moves
+            CommandType::PushIndexed => {
+                "@SP\nA=M-1\nD=M\n".to_string() + &add_base + "A=D\nD=M\n@SP\nA=M-1\nM=D\n"
+            }
+            CommandType::PopIndexed => {
+                let address = self.frame_register;
+                Self::pop_d()
+                    + &add_base
+                    + &Self::store_temp_var(address)
+                    + &Self::pop_d()
+                    + &format!("@R{address}\nA=M\nM=D\n")
+            }
+            _ => return Err(Self::wrong_command(&command, "an indexed push or pop")),
+        } + &comment;
+
+        self.emit(&result)
+    }
+
+    /// writes the `debug-print` command to the out_stream, popping the top of the stack into the
+    /// debug address so it can be watched while the program runs
+    pub fn write_debug_print(&mut self) -> Result<(), VmError> {
+        let address = self.debug_address;
+        self.emit(&(Self::pop_d() + &format!("@{address}\nM=D\n// debug-print\n\n")))
+    }
+
+    /// writes a `push-range` or `pop-range` command to the out_stream, moving `count` consecutive
+    /// words between the stack and segment[start..start + count] with a loop instead of unrolling
+    /// them. `pop-range` fills the range from the top down so it undoes the matching `push-range`
+    pub fn write_push_pop_range(
+        &mut self,
+        command: CommandType,
+        segment: String,
+        start: i16,
+        count: i16,
+    ) -> Result<(), VmError> {
+        Self::check_segment(&segment)?;
+        let comment = format!("// {command} {segment} {start} {count}\n\n");
+        let capacity = range_capacity(&segment, start).filter(|_| count >= 0);
+        if capacity.is_none_or(|capacity| i32::from(count) > capacity) {
+            let (_, expected) = segment_bounds(&segment);
+            return Err(VmError::rejected(format!(
+                    "range {start} {count} is out of bounds for segment '{segment}', expected {expected}"
+                )));
+        }
+        let offset = match command {
+            CommandType::PushRange => Some(start),
+            // pop-range starts one past the end and walks down
+            CommandType::PopRange => start.checked_add(count),
+            _ => return Err(Self::wrong_command(&command, "a ranged push or pop")),
+        };
+        let offset = offset.ok_or_else(|| {
+            VmError::rejected(format!("range {start} {count} runs past the end of memory"))
+        })?;
+        let address = Self::load_segment_address(&segment, offset).ok_or_else(|| {
+            VmError::rejected(format!("segment '{segment}' can't be used with {command}"))
+        })?;
+
+        let label_loop = self.get_label(LabelType::Internal, Some(&"RANGE".to_string()))?;
+        let label_end = format!("{label_loop}.END");
+
+        // the frame register walks the segment, the extra register counts the words left to move
+        let (walk, left) = (self.frame_register, self.extra_register);
+        let step = match command {
+            CommandType::PushRange => {
+                format!("@R{walk}\nA=M\nD=M\n") + &Self::push_d() + &format!("@R{walk}\nM=M+1\n")
+            }
+            _ => Self::pop_d() + &format!("@R{walk}\nAM=M-1\nM=D\n"),
+        };
+        let result = address
+            + &Self::store_temp_var(walk)
+            + &format!("@{count}\nD=A\n@R{left}\nM=D\n")
+            + &format!("({label_loop})\n@R{left}\nD=M\n@{label_end}\nD;JEQ\n")
+            + &step
+            + &format!("@R{left}\nM=M-1\n@{label_loop}\n0;JMP\n({label_end})\n")
+            + &comment;
+
+        self.emit(&result)
+    }
+
     /// pops the bottom two values of the stack and performs the given operation on them, pushing
-    /// the result back onto the stack
+    /// the result back onto the stack.
+    ///
+    /// `add`/`sub`/`neg` are done by the ALU on 16-bit two's complement words and wrap around the
+    /// same way it does, so 32767 + 1 = -32768, -32768 - 1 = 32767 and neg(-32768) = -32768
     fn do_stack_op_two(op: String) -> String {
         Self::pop_d() + &Self::pop_a() + &op + "\n" + &Self::push_d()
     }
@@ -394,108 +1714,236 @@ impl<W: Write + Seek> CodeWriter<W> {
         Self::pop_d() + &op + "\n" + &Self::push_d()
     }
     /// compares the bottom two values on the stack using the assembly jump_op given, pushing
-    /// true(1) if the jump_op condition is met or false(0) otherwise
-    fn do_compare_stack_two(&mut self, jump_op: String) -> String {
-        let label = format!("{jump_op}COMP");
-        let label_true = self.get_label(LabelType::CompareTrue, Some(&label));
-        let label_false = self.get_label(LabelType::CompareFalse, Some(&label));
-        Self::do_stack_op_two(
-            format!(
-                "D=D-A\n @{label_true}\n D;{jump_op}\n D=0\n @END{label_false}\n 0;JMP\n ({label_true})\n D=-1\n (END{label_false})\n"
-            )
-        ) + "// if then\n"
+    /// true(-1) if the jump_op condition is met or false(0) otherwise. the jump is on y - x
+    ///
+    /// only the subtraction and the jump happen inline: setting D and pushing it is done once for
+    /// the whole program by the tail from `compare_tail`, which jumps back through the ret register.
+    /// with `set_inline_compares` all of it is inline instead. `gt`/`lt` can't just subtract, see
+    /// `signed_difference`, so they jump to the tail before it and only `eq` subtracts inline
+    fn do_compare_stack_two(&mut self, op: &str, jump_op: String) -> Result<String, VmError> {
+        let label_ret = self.get_label(LabelType::CompareRet, Some(&op.to_string()))?;
+        let prefix = &self.label_prefix;
+        if self.inline_compares || self.reference_compat {
+            // the whole comparison inline, the result overwrites x: true first, then false if
+            // the jump over it isn't taken
+            let difference = match op {
+                "eq" => "A=A-1\nD=D-M\n".to_string(),
+                _ => Self::signed_difference(&label_ret) + "@SP\nA=M-1\n",
+            };
+            return Ok("@SP\nAM=M-1\nD=M\n".to_string()
+                + &difference
+                + "M=-1\n"
+                + &format!("@{label_ret}\nD;{jump_op}\n@SP\nA=M-1\nM=0\n({label_ret})\n"));
+        }
+        self.uses_compare_tail = true;
+
+        let compare = Self::store_temp_var(self.ret_register);
+        let compare = if op == "eq" {
+            compare
+                + &Self::pop_d()
+                + &Self::pop_a()
+                + &format!(
+                    "D=D-A\n @{prefix}__CMP_TRUE\n D;{jump_op}\n @{prefix}__CMP_FALSE\n 0;JMP\n"
+                )
+        } else {
+            self.uses_signed_compare = true;
+            let test = format!("{prefix}__CMP_{}", op.to_uppercase());
+            compare + &format!("@{test}\n D=A\n @{prefix}__CMP_SIGNED\n 0;JMP\n")
+        };
+
+        Ok(
+            format!("@{label_ret}\nD=A\n")
+                + &compare
+                + &format!("({label_ret})\n")
+                + "// if then\n",
+        )
+    }
+
+    /// with y in D and SP pointing at it, sets D to something with the same sign as y - x without
+    /// overflowing. the subtraction alone gets it wrong when x and y have opposite signs and are
+    /// far enough apart (32767 - -2 wraps to -32767), but then y's sign is the answer, so it's
+    /// only done for operands with the same sign. labels are made from `base`
+    fn signed_difference(base: &str) -> String {
+        format!("@{base}.YNEG\nD;JLT\n")
+            + &format!("@SP\nA=M-1\nD=M\n@{base}.POS\nD;JLT\n@{base}.SAME\n0;JMP\n")
+            + &format!("({base}.YNEG)\n@SP\nA=M-1\nD=M\n@{base}.NEG\nD;JGE\n")
+            + &format!("({base}.SAME)\n@SP\nA=M\nD=M\nA=A-1\nD=D-M\n@{base}.TEST\n0;JMP\n")
+            + &format!("({base}.NEG)\nD=-1\n@{base}.TEST\n0;JMP\n")
+            + &format!("({base}.POS)\nD=1\n({base}.TEST)\n")
+    }
+
+    /// logical `land`/`lor` on the bottom two values of the stack. any non-zero value counts as
+    /// true and the result is always canonical, true(-1) or false(0), unlike bitwise `and`/`or`
+    /// which give 1 & 2 = 0. as soon as one operand decides the result (`short_jump` holds for it)
+    /// the other isn't looked at and `short_value` is pushed, otherwise `other_value` is
+    fn do_logic_stack_two(
+        &mut self,
+        short_jump: &str,
+        short_value: i16,
+        other_value: i16,
+    ) -> Result<String, VmError> {
+        let label_short = self.get_label(LabelType::Internal, Some(&"LOGIC".to_string()))?;
+        let label_end = format!("{label_short}.END");
+
+        // SP is left pointing at y, so x (where the result goes) is at SP-1 on every path
+        Ok(Self::decrement_sp()
+            + "D=M\n"
+            + &format!("@{label_short}\nD;{short_jump}\n")
+            + "@SP\nA=M-1\nD=M\n"
+            + &format!("@{label_short}\nD;{short_jump}\n")
+            + &format!("D={other_value}\n@{label_end}\n0;JMP\n")
+            + &format!("({label_short})\nD={short_value}\n({label_end})\n")
+            + "@SP\nA=M-1\nM=D\n")
+    }
+
+    /// the true/false setters shared by every comparison, see `do_compare_stack_two`. `gt`/`lt`
+    /// come in at `__CMP_SIGNED` with their test's address in D, which is kept in the frame
+    /// register (only ever used within a single command) while the difference is worked out
+    fn compare_tail(&self) -> String {
+        let prefix = &self.label_prefix;
+
+        let signed = if self.uses_signed_compare {
+            let test = |op: &str, jump: &str| {
+                format!("({prefix}__CMP_{op})\n@{prefix}__CMP_TRUE\nD;{jump}\n")
+                    + &format!("@{prefix}__CMP_FALSE\n0;JMP\n")
+            };
+            format!("({prefix}__CMP_SIGNED)\n")
+                + &Self::store_temp_var(self.frame_register)
+                + "@SP\nAM=M-1\nD=M\n"
+                + &Self::signed_difference(&format!("{prefix}__CMP_SIGNED"))
+                + "@SP\nM=M-1\n"
+                + &Self::get_temp_var(self.frame_register, "A")
+                + "0;JMP\n"
+                + &test("GT", "JLT")
+                + &test("LT", "JGT")
+        } else {
+            String::new()
+        };
+
+        signed
+            + &format!("({prefix}__CMP_TRUE)\nD=-1\n@{prefix}__CMP_PUSH\n0;JMP\n")
+            + &format!("({prefix}__CMP_FALSE)\nD=0\n({prefix}__CMP_PUSH)\n")
+            + &Self::push_d()
+            + &Self::get_temp_var(self.ret_register, "A")
+            + "0;JMP\n"
     }
 
     /// writes the provided VM arithmetic command to the out_stream
-    pub fn write_arithmetic(&mut self, command: String) -> io::Result<()> {
-        let result = match command.as_str() {
+    pub fn write_arithmetic(&mut self, command: String) -> Result<(), VmError> {
+        let mut result = match command.as_str() {
             "add" => Self::do_stack_op_two("D=D+A".to_string()),
             "sub" => Self::do_stack_op_two("D=A-D".to_string()),
             "neg" => Self::do_stack_op_one("D=-D".to_string()),
-            "eq" => self.do_compare_stack_two("JEQ".to_string()),
-            "gt" => self.do_compare_stack_two("JLT".to_string()),
-            "lt" => self.do_compare_stack_two("JGT".to_string()),
+            "eq" => self.do_compare_stack_two("eq", "JEQ".to_string())?,
+            "gt" => self.do_compare_stack_two("gt", "JLT".to_string())?,
+            "lt" => self.do_compare_stack_two("lt", "JGT".to_string())?,
             "and" => Self::do_stack_op_two("D=D&A".to_string()),
             "or" => Self::do_stack_op_two("D=D|A".to_string()),
+            "land" => self.do_logic_stack_two("JEQ", 0, -1)?,
+            "lor" => self.do_logic_stack_two("JNE", -1, 0)?,
             "not" => Self::do_stack_op_one("D=!D".to_string()),
-            _ => panic!("Unexpected arithmetic command encountered: {}", command),
+            _ => {
+                return Err(VmError::UnknownCommand {
+                    token: command,
+                    line: 0,
+                })
+            }
         };
+        if self.reference_compat {
+            result.push_str(&format!("// {command}\n"));
+        }
 
-        self.out_stream.write_all(result.as_bytes())?;
+        self.emit(&result)?;
         Ok(())
     }
 
     /// writes the `label` VM command to the out_stream
-    pub fn write_label(&mut self, label_name: String) -> io::Result<()> {
+    pub fn write_label(&mut self, label_name: String) -> Result<(), VmError> {
         let comment = format!("// label {label_name}\n");
-        let label = self.get_label(LabelType::FunctionLabel, Some(&label_name));
-        self.out_stream
-            .write_all(format!("({label})\n{comment}").as_bytes())
+        let label = self.get_label(LabelType::FunctionLabel, Some(&label_name))?;
+        self.emit(&format!("({label})\n{comment}"))
+    }
+    /// resolves the label a `goto`/`if-goto` jumps to. a leading `@` (`goto @LOOP`) means the
+    /// label is raw assembly and is used verbatim instead of being namespaced to the function
+    fn get_jump_target(&mut self, label_name: &String) -> Result<String, VmError> {
+        match label_name.strip_prefix('@') {
+            Some(raw_label) => Ok(raw_label.to_string()),
+            None => self.get_label(LabelType::FunctionLabel, Some(label_name)),
+        }
     }
+
     /// writes the `goto` VM command to the out_stream
-    pub fn write_goto(&mut self, label_name: String) -> io::Result<()> {
+    pub fn write_goto(&mut self, label_name: String) -> Result<(), VmError> {
         let comment = format!("// goto {label_name}\n");
-        let label = self.get_label(LabelType::FunctionLabel, Some(&label_name));
+        let label = self.get_jump_target(&label_name)?;
 
         let output = format!("@{label}\n 0;JMP\n{comment}");
-        self.out_stream.write_all(output.as_bytes())
+        self.emit(&output)
     }
     /// writes the `if-goto` VM command to the out_stream
-    pub fn write_if(&mut self, label_name: String) -> io::Result<()> {
+    pub fn write_if(&mut self, label_name: String) -> Result<(), VmError> {
         let comment = format!("// if-goto {label_name}\n");
-        let label = self.get_label(LabelType::FunctionLabel, Some(&label_name));
+        let label = self.get_jump_target(&label_name)?;
 
         let output = Self::pop_d() + &format!("@{label}\n D;JNE\n") + &comment;
 
-        self.out_stream.write_all(output.as_bytes())
+        self.emit(&output)
     }
 
     /// set reg to temp_var i
-    fn get_temp_var(i: usize, reg: &str) -> String {
+    fn get_temp_var(i: u8, reg: &str) -> String {
         let i_str = i.to_string();
         format!("@R{i_str}\n{reg}=M\n") // get temp_var i and set reg to that value
     }
 
     /// store D in temp_var i
-    fn store_temp_var(i: usize) -> String {
+    fn store_temp_var(i: u8) -> String {
         let i_str = i.to_string();
         format!("@R{i_str}\nM=D\n")
     }
 
     /// writes the `return` VM command to the out_stream
-    pub fn write_return(&mut self) -> io::Result<()> {
+    ///
+    /// the caller's frame sits just below LCL (see `write_call`), so with frame = LCL:
+    /// THAT = *(frame-1), THIS = *(frame-2), ARG = *(frame-3), LCL = *(frame-4) and the return
+    /// address is *(frame-5). the return address is saved first since the return value
+    /// overwrites *ARG, which is the same slot when the function takes no arguments
+    pub fn write_return(&mut self) -> Result<(), VmError> {
         let comment = "// return\n";
         let result = "@LCL\nD=M\n".to_owned()
-            + &Self::store_temp_var(13) // R13 is frame
+            + &Self::store_temp_var(self.frame_register) // frame
             + "@5\nA=D-A\nD=M\n" // D = *(frame-5)
-            + &Self::store_temp_var(14) // R14 is ret_address
+            + &Self::store_temp_var(self.ret_register) // ret_address
 
             + &Self::pop_d() // get the return value
             + "@ARG\nA=M\nM=D\n" // set head of callee stack to be the return value
             + "D=A\n@SP\nM=D+1\n" // set SP to ARG + 1 (new head containing the return value)
 
-            + &Self::get_temp_var(13, "D")
+            + &Self::get_temp_var(self.frame_register, "D")
             + "A=D-1\nD=M\n@THAT\nM=D\n" // restore THAT
 
-            + &Self::get_temp_var(13, "D")
+            + &Self::get_temp_var(self.frame_register, "D")
             + "@2\nA=D-A\nD=M\n@THIS\nM=D\n" // restore THIS
 
-            + &Self::get_temp_var(13, "D")
+            + &Self::get_temp_var(self.frame_register, "D")
             + "@3\nA=D-A\nD=M\n@ARG\nM=D\n" // restore ARG
 
-            + &Self::get_temp_var(13, "D")
+            + &Self::get_temp_var(self.frame_register, "D")
             + "@4\nA=D-A\nD=M\n@LCL\nM=D\n" // restore LCL
 
-            + &Self::get_temp_var(14, "A")
+            + &Self::get_temp_var(self.ret_register, "A")
             + "0;JMP\n" // jump to ret_address
             + comment;
 
-        self.out_stream.write_all(result.as_bytes())
+        self.emit(&result)
     }
 
     /// writes the `call` VM command to the out_stream
-    pub fn write_call(&mut self, function_name: String, n_vars: i16) -> io::Result<()> {
-        let ret_address = self.get_label(LabelType::FunctionRet, Some(&function_name));
+    ///
+    /// pushes the frame `return address, LCL, ARG, THIS, THAT` in that order, so `write_return`
+    /// finds each one at a fixed offset below the callee's LCL
+    pub fn write_call(&mut self, function_name: String, n_vars: i16) -> Result<(), VmError> {
+        let ret_address = self.get_label(LabelType::FunctionRet, Some(&function_name))?;
         let n_vars_str = n_vars.to_string();
         let comment = format!("// call {function_name} {n_vars_str}\n");
         let result = format!("@{ret_address}\nD=A\n") + &Self::push_d()
@@ -511,29 +1959,1902 @@ impl<W: Write + Seek> CodeWriter<W> {
             + &format!("({ret_address})\n") // sets the ret_address label
             + &comment;
 
-        self.out_stream.write_all(result.as_bytes())
+        self.emit(&result)
     }
 
-    pub fn write_function(&mut self, function_name: String, n_locals: i16) -> io::Result<()> {
+    pub fn write_function(&mut self, function_name: String, n_locals: i16) -> Result<(), VmError> {
+        Self::check_user_symbol(&function_name)?;
+        if !is_valid_symbol(&function_name) {
+            return Err(VmError::rejected(format!(
+                "'{function_name}' is not a valid function name"
+            )));
+        }
+        // the labels these count are scoped to the function, so its numbering can start over
+        self.cur_func = function_name.clone();
+        self.call_count = self.counter_base;
+        self.compare_count = self.counter_base;
         let mut result = format!("({function_name})\n");
         let n_locals_str = n_locals.to_string();
         let comment = format!("// function {function_name} {n_locals_str}\n");
 
-        result.push_str(&Self::push_locals(n_locals));
+        if self.zero_locals {
+            let locals = self.push_locals(n_locals)?;
+            result.push_str(&locals);
+        } else if n_locals > 0 {
+            result.push_str(&format!("@{n_locals}\nD=A\n@SP\nM=D+M\n"));
+        }
         result.push_str(&comment);
 
-        self.out_stream.write_all(result.as_bytes())
+        self.emit(&result)
+    }
+
+    /// setup assembly for setting the stack pointer and jumps to the entry point (`Sys.init`)
+    pub fn write_init(&mut self) -> Result<(), VmError> {
+        self.write_sp_init()?;
+        self.write_entry_call()
+    }
+
+    /// the first half of `write_init`, points SP at the start of the stack (256)
+    pub fn write_sp_init(&mut self) -> Result<(), VmError> {
+        self.emit("@256\nD=A\n@SP\nM=D\n")
+    }
+
+    /// the second half of `write_init`, transfers control to the entry point
+    pub fn write_entry_call(&mut self) -> Result<(), VmError> {
+        if self.emulator_builtins {
+            // no file is being translated yet, so give the return label a namespace of its own
+            let namespace = std::mem::replace(&mut self.namespace, "Bootstrap".to_string());
+            let result = self.write_call(self.entry_point.clone(), 0);
+            self.namespace = namespace;
+            return result;
+        }
+
+        let entry_point = &self.entry_point;
+        self.emit(&format!("@{entry_point}\n0;JMP\n"))
+    }
+
+    /// marks the start of the `n`th translated VM command for cycle profilers
+    pub fn write_profile_start(&mut self, n: usize) -> Result<(), VmError> {
+        let prefix = &self.label_prefix;
+        self.emit(&format!("({prefix}__vm_start.{n})\n"))
+    }
+
+    /// marks the end of the `n`th translated VM command for cycle profilers
+    pub fn write_profile_end(&mut self, n: usize) -> Result<(), VmError> {
+        let prefix = &self.label_prefix;
+        self.emit(&format!("({prefix}__vm_end.{n})\n"))
+    }
+
+    /// writes a `(__checkpoint.name)` label to break on or profile up to, without touching the
+    /// stack or any register. a name used again gets a count after it (`__checkpoint.name.1`) so
+    /// every checkpoint stays unique
+    pub fn write_checkpoint(&mut self, name: &str) -> Result<(), VmError> {
+        let prefix = &self.label_prefix;
+        let label = match self.checkpoints.get(name) {
+            None => format!("{prefix}__checkpoint.{name}"),
+            Some(n) => format!("{prefix}__checkpoint.{name}.{n}"),
+        };
+        if !is_valid_symbol(&label) {
+            return Err(VmError::rejected(format!(
+                "'{name}' is not a valid checkpoint name"
+            )));
+        }
+
+        *self.checkpoints.entry(name.to_string()).or_insert(0) += 1;
+        self.emit(&format!("({label})\n"))
+    }
+
+    /// writes generated assembly to the out_stream, everything the writer outputs goes through here
+    fn emit(&mut self, asm: &str) -> Result<(), VmError> {
+        let count = asm.lines().filter(|line| is_instruction(line)).count();
+        let function = match self.cur_func.as_str() {
+            "" => analysis::GLOBAL_SCOPE,
+            function => function,
+        };
+        self.instruction_count += count;
+        if count > 0 {
+            *self.function_sizes.entry(function.to_string()).or_default() += count;
+        }
+
+        let reference;
+        let asm = if self.reference_compat {
+            reference = Self::reference_format(asm);
+            &reference
+        } else {
+            asm
+        };
+
+        if self.interleave {
+            let indented: String = asm
+                .lines()
+                .map(|line| match line.trim() {
+                    "" => "\n".to_string(),
+                    line => format!("    {line}\n"),
+                })
+                .collect();
+            self.out_stream.write_all(indented.as_bytes())?;
+            return Ok(());
+        }
+        Ok(self.out_stream.write_all(asm.as_bytes())?)
+    }
+
+    /// lays out `asm` the way the reference translator does: the comments first, then one
+    /// instruction or label per line without indentation, trailing comments or blank lines
+    fn reference_format(asm: &str) -> String {
+        let (comments, code): (Vec<&str>, Vec<&str>) = asm
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .partition(|line| line.starts_with("//"));
+
+        comments
+            .into_iter()
+            .chain(
+                code.into_iter()
+                    .map(|line| line.split("//").next().unwrap_or_default().trim_end()),
+            )
+            .map(|line| format!("{line}\n"))
+            .collect()
+    }
+
+    /// the RAM address `debug-print` stores to, `DEFAULT_DEBUG_ADDRESS` by default
+    pub fn set_debug_address(&mut self, address: i16) -> Result<(), VmError> {
+        if address < 0 {
+            return Err(VmError::rejected(format!(
+                "{address} is not a valid RAM address"
+            )));
+        }
+        self.debug_address = address;
+        Ok(())
+    }
+
+    /// when enabled, `write_push_pop` errors on any index outside of its segment's canonical
+    /// bounds instead of translating it, see `check_index`. `temp` and `pointer` are always
+    /// checked
+    pub fn set_strict_indices(&mut self, strict_indices: bool) {
+        self.strict_indices = strict_indices;
+    }
+
+    /// when enabled, comparisons are done entirely inline instead of jumping to the shared tail
+    /// and back through the ret register
+    pub fn set_inline_compares(&mut self, inline_compares: bool) {
+        self.inline_compares = inline_compares;
+    }
+
+    pub fn inline_compares(&self) -> bool {
+        self.inline_compares
+    }
+
+    /// when enabled, the output follows the conventions of the reference VM translator as far as
+    /// this one can: each command's comment comes before its code (arithmetic included),
+    /// comparisons are done inline instead of through the shared tail and nothing is indented or
+    /// commented past the instruction. labels are still named the way `get_label` names them
+    pub fn set_reference_compat(&mut self, reference_compat: bool) {
+        self.reference_compat = reference_compat;
+    }
+
+    /// when enabled, all generated assembly is indented so the headers from
+    /// `write_source_header` group it by the VM command it came from
+    pub fn set_interleave(&mut self, interleave: bool) {
+        self.interleave = interleave;
+    }
+
+    /// when enabled, the comment after a push or pop also names the register or address it
+    /// resolved to, `// push local 3 -> LCL+3`
+    pub fn set_resolve_comments(&mut self, resolve_comments: bool) {
+        self.resolve_comments = resolve_comments;
+    }
+
+    /// when disabled, `static i` is the fixed address 16 + i instead of the symbol `File.i`, so
+    /// every file shares the same statics. only meant for small test programs whose expected RAM
+    /// was worked out by hand
+    pub fn set_static_namespace(&mut self, static_namespace: bool) {
+        self.static_namespace = static_namespace;
+    }
+
+    /// writes a VM source line as a comment, left unindented so it heads the assembly that follows
+    pub fn write_source_header(&mut self, source: &str) -> Result<(), VmError> {
+        Ok(self
+            .out_stream
+            .write_all(format!("\n// {source}\n").as_bytes())?)
+    }
+
+    /// how many real instructions (not labels or comments) have been written, which is how many
+    /// words of ROM the program takes up once assembled
+    pub fn instruction_count(&self) -> usize {
+        self.instruction_count
+    }
+
+    /// writes everything `fragment` translated to the out_stream as is, taking on its instruction
+    /// counts and whether it needs the comparison tail, so `write_end` still covers it
+    pub fn append(&mut self, mut fragment: CodeWriter<Vec<u8>>) -> io::Result<()> {
+        self.out_stream.write_all(fragment.get_mut())?;
+
+        self.instruction_count += fragment.instruction_count;
+        self.uses_compare_tail |= fragment.uses_compare_tail;
+        self.uses_signed_compare |= fragment.uses_signed_compare;
+        for (function, size) in fragment.function_sizes {
+            *self.function_sizes.entry(function).or_default() += size;
+        }
+        Ok(())
+    }
+
+    /// how many instructions have been written for each function, with the bootstrap and any
+    /// code outside of a function under `analysis::GLOBAL_SCOPE`
+    pub fn per_function_sizes(&self) -> &BTreeMap<String, usize> {
+        &self.function_sizes
+    }
+
+    /// writes a standalone `// comment` line to the out_stream
+    pub fn write_comment(&mut self, comment: &str) -> Result<(), VmError> {
+        self.emit(&format!("// {comment}\n"))
+    }
+
+    /// makes `//!name args` comments call `handler` with `args`, writing the assembly it returns.
+    /// replaces any handler already registered for `name`, `label` included
+    pub fn register_directive(
+        &mut self,
+        name: &str,
+        handler: impl FnMut(&str) -> Result<String, VmError> + Send + 'static,
+    ) {
+        self.directives.insert(name.to_string(), Box::new(handler));
+    }
+
+    /// writes a directive (everything after the `//!`) with its registered handler. unknown
+    /// directives are only warned about, since they might be meant for some other tool
+    pub fn write_directive(&mut self, directive: String) -> Result<(), VmError> {
+        let (name, args) = directive.split_once(' ').unwrap_or((&directive, ""));
+        let Some(handler) = self.directives.get_mut(name) else {
+            return Ok(self.warn(&format!(
+                "Warning: ignoring unknown directive `{DIRECTIVE_PREFIX}{name}`\n"
+            ))?);
+        };
+
+        let result = handler(args.trim())?;
+        self.emit(&(result + &format!("// {DIRECTIVE_PREFIX}{directive}\n\n")))
+    }
+
+    /// flushes anything still buffered in the out_stream
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out_stream.flush()
+    }
+
+    /// writes a neverending loop to the out_stream, followed by any shared code the translated
+    /// commands jump to
+    pub fn write_end(&mut self) -> Result<(), VmError> {
+        self.cur_func.clear();
+        let prefix = &self.label_prefix;
+        self.emit(&format!("({prefix}VMEND)\n@{prefix}VMEND\n0;JMP\n"))?;
+
+        // nothing falls through into here, it's only ever jumped to by comparisons
+        self.write_shared_code()
+    }
+
+    /// writes the shared code the translated commands jump to, without the end loop before it.
+    /// `write_end` does this itself, this is for output that doesn't end like a program (a
+    /// library), so whatever comes before it must not fall through into it
+    pub fn write_shared_code(&mut self) -> Result<(), VmError> {
+        self.cur_func.clear();
+        if self.uses_compare_tail {
+            let tail = self.compare_tail();
+            self.emit(&tail)?;
+        }
+        Ok(())
     }
+}
 
-    /// setup assembly for setting the stack pointer and jumps to the `Sys.init`
-    pub fn write_init(&mut self) -> io::Result<()> {
-        self.out_stream
-            .write_all("@256\nD=A\n@SP\nM=D\n@Sys.init\n0;JMP\n".as_bytes())
+/// the code generation side of translation. `CodeWriter` is the Hack backend, anything else
+/// implementing this can be driven by `translate` in its place
+pub trait CodeGen {
+    fn write_arithmetic(&mut self, command: String) -> Result<(), VmError>;
+    fn write_push_pop(
+        &mut self,
+        command: CommandType,
+        segment: String,
+        index: i16,
+    ) -> Result<(), VmError>;
+    fn write_push_pop_range(
+        &mut self,
+        command: CommandType,
+        segment: String,
+        start: i16,
+        count: i16,
+    ) -> Result<(), VmError>;
+    fn write_push_pop_indexed(
+        &mut self,
+        command: CommandType,
+        segment: String,
+    ) -> Result<(), VmError>;
+    fn write_directive(&mut self, directive: String) -> Result<(), VmError>;
+    fn write_debug_print(&mut self) -> Result<(), VmError>;
+    fn write_label(&mut self, label_name: String) -> Result<(), VmError>;
+    fn write_goto(&mut self, label_name: String) -> Result<(), VmError>;
+    fn write_if(&mut self, label_name: String) -> Result<(), VmError>;
+    fn write_function(&mut self, function_name: String, n_locals: i16) -> Result<(), VmError>;
+    fn write_call(&mut self, function_name: String, n_vars: i16) -> Result<(), VmError>;
+    fn write_return(&mut self) -> Result<(), VmError>;
+
+    /// writes any parsed command with the matching `write_*` method, erroring if it's missing an
+    /// argument
+    fn write_command(&mut self, command: &Command) -> Result<(), VmError> {
+        let missing = |position: usize| VmError::MissingArgument {
+            command: command.command_type.clone(),
+            position,
+            line: 0,
+        };
+        let arg1 = || command.arg1.clone().ok_or_else(|| missing(1));
+        let arg2 = || command.arg2.ok_or_else(|| missing(2));
+        let arg3 = || command.arg3.ok_or_else(|| missing(3));
+
+        match &command.command_type {
+            CommandType::Arithmetic(op) => self.write_arithmetic(op.clone()),
+            CommandType::Push | CommandType::Pop => {
+                self.write_push_pop(command.command_type.clone(), arg1()?, arg2()?)
+            }
+            CommandType::Label => self.write_label(arg1()?),
+            CommandType::Goto => self.write_goto(arg1()?),
+            CommandType::If => self.write_if(arg1()?),
+            CommandType::Function => self.write_function(arg1()?, arg2()?),
+            CommandType::Call => self.write_call(arg1()?, arg2()?),
+            CommandType::Return => self.write_return(),
+            CommandType::PushRange | CommandType::PopRange => {
+                self.write_push_pop_range(command.command_type.clone(), arg1()?, arg2()?, arg3()?)
+            }
+            CommandType::PushIndexed | CommandType::PopIndexed => {
+                self.write_push_pop_indexed(command.command_type.clone(), arg1()?)
+            }
+            CommandType::Directive => self.write_directive(arg1()?),
+            CommandType::DebugPrint => self.write_debug_print(),
+            CommandType::Empty => Ok(()),
+        }
     }
+}
 
-    /// writes a neverending loop to the out_stream
-    pub fn write_end(&mut self) -> io::Result<()> {
-        self.out_stream
-            .write_all("(VMEND)\n@VMEND\n0;JMP\n".as_bytes())
+impl<W: Write> CodeGen for CodeWriter<W> {
+    fn write_arithmetic(&mut self, command: String) -> Result<(), VmError> {
+        CodeWriter::write_arithmetic(self, command)
+    }
+    fn write_push_pop(
+        &mut self,
+        command: CommandType,
+        segment: String,
+        index: i16,
+    ) -> Result<(), VmError> {
+        CodeWriter::write_push_pop(self, command, segment, index)
+    }
+    fn write_push_pop_range(
+        &mut self,
+        command: CommandType,
+        segment: String,
+        start: i16,
+        count: i16,
+    ) -> Result<(), VmError> {
+        CodeWriter::write_push_pop_range(self, command, segment, start, count)
+    }
+    fn write_push_pop_indexed(
+        &mut self,
+        command: CommandType,
+        segment: String,
+    ) -> Result<(), VmError> {
+        CodeWriter::write_push_pop_indexed(self, command, segment)
+    }
+    fn write_directive(&mut self, directive: String) -> Result<(), VmError> {
+        CodeWriter::write_directive(self, directive)
+    }
+    fn write_debug_print(&mut self) -> Result<(), VmError> {
+        CodeWriter::write_debug_print(self)
+    }
+    fn write_label(&mut self, label_name: String) -> Result<(), VmError> {
+        CodeWriter::write_label(self, label_name)
+    }
+    fn write_goto(&mut self, label_name: String) -> Result<(), VmError> {
+        CodeWriter::write_goto(self, label_name)
+    }
+    fn write_if(&mut self, label_name: String) -> Result<(), VmError> {
+        CodeWriter::write_if(self, label_name)
+    }
+    fn write_function(&mut self, function_name: String, n_locals: i16) -> Result<(), VmError> {
+        CodeWriter::write_function(self, function_name, n_locals)
+    }
+    fn write_call(&mut self, function_name: String, n_vars: i16) -> Result<(), VmError> {
+        CodeWriter::write_call(self, function_name, n_vars)
+    }
+    fn write_return(&mut self) -> Result<(), VmError> {
+        CodeWriter::write_return(self)
+    }
+}
+
+/// `Write` adapter that turns every `\n` into `\r\n`, for tools that insist on windows line
+/// endings. a `\r\n` that's already there is passed through untouched
+#[derive(Debug)]
+pub struct CrlfWriter<W: Write> {
+    inner: W,
+    last_byte: Option<u8>,
+}
+
+impl<W: Write> CrlfWriter<W> {
+    pub fn new(inner: W) -> CrlfWriter<W> {
+        CrlfWriter {
+            inner,
+            last_byte: None,
+        }
+    }
+}
+
+impl<W: Write> Write for CrlfWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut converted = Vec::with_capacity(buf.len());
+
+        for &byte in buf {
+            if byte == b'\n' && self.last_byte != Some(b'\r') {
+                converted.push(b'\r');
+            }
+            converted.push(byte);
+            self.last_byte = Some(byte);
+        }
+
+        self.inner.write_all(&converted)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// one line of generated assembly, see `AsmLines`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AsmLine {
+    /// `@value`
+    A(String),
+    /// `dest=comp;jump`, dest and jump being optional
+    C {
+        dest: Option<String>,
+        comp: String,
+        jump: Option<String>,
+    },
+    /// `(name)`
+    Label(String),
+    /// `// text`, the text without the slashes
+    Comment(String),
+}
+
+impl AsmLine {
+    /// the lines of assembly in `line`, an instruction or label with a comment after it gives
+    /// both. whitespace is insignificant, so a blank line gives nothing
+    pub fn parse(line: &str) -> Vec<AsmLine> {
+        let (code, comment) = match line.split_once("//") {
+            Some((code, comment)) => (code, Some(comment.trim())),
+            None => (line, None),
+        };
+        let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+
+        let mut lines = Vec::new();
+        if let Some(label) = code.strip_prefix('(') {
+            lines.push(AsmLine::Label(label.trim_end_matches(')').to_string()));
+        } else if let Some(value) = code.strip_prefix('@') {
+            lines.push(AsmLine::A(value.to_string()));
+        } else if !code.is_empty() {
+            let (dest, rest) = match code.split_once('=') {
+                Some((dest, rest)) => (Some(dest.to_string()), rest),
+                None => (None, code.as_str()),
+            };
+            let (comp, jump) = match rest.split_once(';') {
+                Some((comp, jump)) => (comp, Some(jump.to_string())),
+                None => (rest, None),
+            };
+            lines.push(AsmLine::C {
+                dest,
+                comp: comp.to_string(),
+                jump,
+            });
+        }
+        if let Some(comment) = comment {
+            lines.push(AsmLine::Comment(comment.to_string()));
+        }
+        lines
+    }
+}
+
+/// formats the line back into assembly
+impl fmt::Display for AsmLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AsmLine::A(value) => write!(f, "@{value}"),
+            AsmLine::C { dest, comp, jump } => {
+                if let Some(dest) = dest {
+                    write!(f, "{dest}=")?;
+                }
+                write!(f, "{comp}")?;
+                if let Some(jump) = jump {
+                    write!(f, ";{jump}")?;
+                }
+                Ok(())
+            }
+            AsmLine::Label(name) => write!(f, "({name})"),
+            AsmLine::Comment(text) => write!(f, "// {text}"),
+        }
+    }
+}
+
+/// `Write` target that keeps the assembly written to it as `AsmLine`s instead of text, for
+/// `CodeWriter::new(AsmLines::new())`
+#[derive(Debug, Default)]
+pub struct AsmLines {
+    lines: Vec<AsmLine>,
+    /// anything written after the last newline
+    partial: Vec<u8>,
+}
+
+impl AsmLines {
+    pub fn new() -> AsmLines {
+        AsmLines::default()
+    }
+
+    /// the complete lines written so far
+    pub fn lines(&self) -> &[AsmLine] {
+        &self.lines
+    }
+
+    /// every line written, including one that wasn't ended by a newline
+    pub fn into_lines(mut self) -> Vec<AsmLine> {
+        let partial = String::from_utf8_lossy(&self.partial).into_owned();
+        self.lines.extend(AsmLine::parse(&partial));
+        self.lines
+    }
+}
+
+impl Write for AsmLines {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.partial.extend_from_slice(buf);
+
+        while let Some(end) = self.partial.iter().position(|&byte| byte == b'\n') {
+            let line: Vec<u8> = self.partial.drain(..=end).collect();
+            self.lines
+                .extend(AsmLine::parse(&String::from_utf8_lossy(&line)));
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::Simulator;
+
+    /// translates `source` as the file `Test` and ends it like a program, after `configure` has
+    /// set the writer up
+    fn translate_with(source: &str, configure: impl FnOnce(&mut CodeWriter<Vec<u8>>)) -> String {
+        let mut writer = CodeWriter::new(Vec::new());
+        writer.set_warning_sink(Box::new(io::sink()));
+        writer.set_namespace("Test".to_string());
+        configure(&mut writer);
+        translate(source, &mut writer).unwrap();
+        writer.write_end().unwrap();
+
+        String::from_utf8(writer.get_mut().clone()).unwrap()
+    }
+
+    #[test]
+    fn keep_empty_stops_on_blank_and_comment_lines() {
+        let mut parser = Parser::new(io::Cursor::new("push constant 1\n\n// note\nadd\n"));
+        parser.set_keep_empty(true);
+
+        let types: Vec<CommandType> = parser
+            .map(|result| result.unwrap().1.command_type)
+            .collect();
+        assert_eq!(
+            types,
+            [
+                CommandType::Push,
+                CommandType::Empty,
+                CommandType::Empty,
+                CommandType::Arithmetic("add".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn blank_and_comment_lines_are_skipped_by_default() {
+        let commands = parse_all("push constant 1\n\n// note\nadd\n").unwrap();
+        assert_eq!(commands.len(), 2);
+    }
+
+    #[test]
+    fn label_prefix_applies_to_generated_labels_only() {
+        let source = "push constant 1\npush constant 2\neq\nlabel LOOP\ngoto LOOP\n";
+        let asm = translate_with(source, |writer| writer.set_label_prefix("p1_"));
+
+        assert!(asm.contains("(p1_Test.$IF.eq.0)"));
+        assert!(asm.contains("(p1___CMP_TRUE)"));
+        assert!(asm.contains("(p1_VMEND)"));
+        // the VM's own label keeps its name
+        assert!(asm.contains("(Test.$LOOP)"));
+        let unprefixed = asm
+            .lines()
+            .filter(|line| line.starts_with('(') && !line.starts_with("(p1_"));
+        assert_eq!(unprefixed.collect::<Vec<_>>(), ["(Test.$LOOP)"]);
+    }
+
+    fn translate_source(source: &str) -> String {
+        translate_with(source, |_| {})
+    }
+
+    /// assembles `asm` and runs it from the top with SP at 256 and then `ram` set, until it
+    /// reaches its end loop
+    fn run_asm(asm: &str, ram: &[(usize, i16)]) -> Simulator {
+        let mut simulator = Simulator::new(assembler::assemble(asm).unwrap());
+        simulator.ram[0] = 256;
+        for &(address, value) in ram {
+            simulator.ram[address] = value;
+        }
+        simulator.run(100_000);
+        assert!(simulator.halted(), "the program never reached its end loop");
+
+        simulator
+    }
+
+    fn run_source(source: &str, ram: &[(usize, i16)]) -> Simulator {
+        run_asm(&translate_source(source), ram)
+    }
+
+    /// a sink that can still be read from after a writer has taken it
+    #[derive(Clone, Default)]
+    struct SharedBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn warnings_go_to_the_warning_sink() {
+        let sink = SharedBuffer::default();
+        let asm = translate_with("//!bogus 1\npush constant 1\n", |writer| {
+            writer.set_warning_sink(Box::new(sink.clone()));
+        });
+
+        let warnings = String::from_utf8(sink.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(warnings, "Warning: ignoring unknown directive `//!bogus`\n");
+        assert!(!asm.contains("Warning"));
+    }
+
+    #[test]
+    fn range_copies_argument_to_local() {
+        let source = "push-range argument 0 4\npop-range local 0 4\n";
+        let (lcl, arg) = (300, 400);
+        let ram = [
+            (1, lcl),
+            (2, arg),
+            (400, 10),
+            (401, 20),
+            (402, 30),
+            (403, 40),
+        ];
+        let simulator = run_source(source, &ram);
+
+        assert_eq!(simulator.ram[300..304], [10, 20, 30, 40]);
+        assert_eq!(simulator.ram[0], 256);
+    }
+
+    #[test]
+    fn ranges_past_the_end_of_their_segment_are_rejected() {
+        for source in [
+            "push-range temp 6 5",
+            "pop-range local 32767 5",
+            "push-range temp 8 0",
+        ] {
+            assert!(
+                matches!(parse_all(source), Err(VmError::BadIndex { .. })),
+                "{source}"
+            );
+        }
+
+        let mut writer = CodeWriter::new(Vec::new());
+        let pop = writer.write_push_pop_range(CommandType::PopRange, "local".to_string(), 32767, 1);
+        assert!(matches!(pop.unwrap_err(), VmError::Translate { .. }));
+        let push = writer.write_push_pop_range(CommandType::PushRange, "temp".to_string(), 6, 5);
+        assert!(matches!(push.unwrap_err(), VmError::Translate { .. }));
+    }
+
+    #[test]
+    fn raw_goto_targets_are_left_unqualified() {
+        let asm = translate_source("goto @INFINITE_LOOP\nif-goto @INFINITE_LOOP\ngoto LOOP\n");
+        let targets: Vec<&str> = asm.lines().filter(|line| line.starts_with('@')).collect();
+
+        assert_eq!(
+            targets,
+            [
+                "@INFINITE_LOOP",
+                "@SP",
+                "@INFINITE_LOOP",
+                "@Test.$LOOP",
+                "@VMEND"
+            ]
+        );
+    }
+
+    #[test]
+    fn emulator_builtins_bootstraps_with_a_call() {
+        let source = "function Sys.init 0
+push constant 6
+push constant 7
+call Math.multiply 2
+label END
+goto END
+";
+        let asm = translate_with(source, |writer| {
+            writer.set_emulator_builtins(true);
+            writer.write_init().unwrap();
+        });
+
+        assert!(asm.contains("@Sys.init\n0;JMP\n(Bootstrap.$ret.0)\n"));
+        // the OS provides Math.multiply, so the call to it is left as it is
+        assert!(asm.contains("@Math.multiply\n0;JMP\n"));
+
+        let source = "function Sys.init 0\nlabel END\ngoto END\n";
+        let asm = translate_with(source, |writer| {
+            writer.set_emulator_builtins(true);
+            writer.write_init().unwrap();
+        });
+        let simulator = run_asm(&asm, &[]);
+        // SP, LCL and ARG after the bootstrap pushed Sys.init's frame
+        assert_eq!(simulator.ram[0..3], [261, 261, 256]);
+    }
+
+    #[test]
+    fn peeking_does_not_consume_the_line() {
+        let mut parser = Parser::new(io::Cursor::new("push constant 1\n// note\nlt\n"));
+        parser.advance().unwrap();
+
+        let peeked = parser.peek_command_type().unwrap();
+        assert_eq!(peeked, Some(CommandType::Arithmetic("lt".to_string())));
+        // still on the first line
+        assert_eq!(parser.command_type().unwrap(), CommandType::Push);
+        assert_eq!(parser.line_raw, 1);
+
+        assert!(parser.advance().unwrap());
+        assert_eq!(Some(parser.command_type().unwrap()), peeked);
+        assert_eq!(parser.line_raw, 3);
+        assert_eq!(parser.peek_command_type().unwrap(), None);
+        assert!(!parser.advance().unwrap());
+    }
+
+    #[test]
+    fn call_and_return_restore_the_callers_frame() {
+        let source = "push constant 7
+push constant 8
+call Foo.add 2
+label END
+goto END
+function Foo.add 2
+push constant 5000
+pop pointer 0
+push constant 6000
+pop pointer 1
+push argument 0
+push argument 1
+add
+pop local 1
+push local 1
+return
+";
+        let sentinels = [(1, 1001), (2, 2002), (3, 3003), (4, 4004)];
+        let simulator = run_source(source, &sentinels);
+
+        assert_eq!(simulator.ram[0], 257);
+        assert_eq!(simulator.ram[256], 15);
+        assert_eq!(simulator.ram[1..5], [1001, 2002, 3003, 4004]);
+    }
+
+    #[test]
+    fn no_zero_locals_only_reserves_them() {
+        let source = "function Foo.f 3\nlabel END\ngoto END\n";
+        let garbage = [(256, 11), (257, 22), (258, 33)];
+
+        let asm = translate_with(source, |writer| writer.set_zero_locals(false));
+        let simulator = run_asm(&asm, &garbage);
+        assert_eq!(simulator.ram[0], 259);
+        assert_eq!(simulator.ram[256..259], [11, 22, 33]);
+
+        let simulator = run_source(source, &garbage);
+        assert_eq!(simulator.ram[0], 259);
+        assert_eq!(simulator.ram[256..259], [0, 0, 0]);
+    }
+
+    #[test]
+    fn many_locals_are_zeroed_in_a_loop() {
+        let source = "function Foo.f 10\nlabel END\ngoto END\n";
+        let asm = translate_source(source);
+
+        assert!(asm.contains("(Foo.f$LOCALS.0)"));
+        assert_eq!(asm.matches("M=0").count(), 1);
+
+        let garbage: Vec<(usize, i16)> = (256..266).map(|address| (address, -1)).collect();
+        let simulator = run_asm(&asm, &garbage);
+        assert_eq!(simulator.ram[0], 266);
+        assert_eq!(simulator.ram[256..266], [0; 10]);
+    }
+
+    #[test]
+    fn crlf_writer_ends_every_line_with_one_carriage_return() {
+        let mut buffer = Vec::new();
+        let mut writer = CodeWriter::new(CrlfWriter::new(&mut buffer));
+        translate("push constant 1\npush constant 2\nadd\n", &mut writer).unwrap();
+        writer.write_end().unwrap();
+        drop(writer);
+
+        let asm = String::from_utf8(buffer).unwrap();
+        assert_eq!(asm.matches('\n').count(), asm.matches("\r\n").count());
+        assert!(!asm.contains("\r\r"));
+
+        // a line ending already split across two writes isn't doubled either
+        let mut buffer = Vec::new();
+        let mut crlf = CrlfWriter::new(&mut buffer);
+        crlf.write_all(b"@1\r").unwrap();
+        crlf.write_all(b"\nD=A\n").unwrap();
+        assert_eq!(buffer, b"@1\r\nD=A\r\n");
+    }
+
+    #[test]
+    fn bootstrap_jumps_to_the_entry_point() {
+        let mut writer = CodeWriter::new(Vec::new());
+        writer.write_init().unwrap();
+        assert_eq!(
+            writer.get_mut().as_slice(),
+            b"@256\nD=A\n@SP\nM=D\n@Sys.init\n0;JMP\n"
+        );
+
+        let mut writer = CodeWriter::new(Vec::new());
+        writer.set_entry_point("Main.main").unwrap();
+        writer.write_init().unwrap();
+        assert!(writer.get_mut().ends_with(b"@Main.main\n0;JMP\n"));
+
+        assert!(writer.set_entry_point("1Main").is_err());
+    }
+
+    #[test]
+    fn parse_all_pairs_commands_with_their_lines() {
+        let source = "// adds two numbers\npush constant 7\n\npush constant 8\nadd // sum\n";
+        let commands = parse_all(source).unwrap();
+
+        let lines: Vec<usize> = commands.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, [2, 4, 5]);
+        assert_eq!(commands[1].1.to_string(), "push constant 8");
+
+        let error = parse_all("push constant 7\npush nowhere 1\npop\n").unwrap_err();
+        assert_eq!(error.to_string(), "line 2: unknown segment `nowhere`");
+    }
+
+    #[test]
+    fn comparisons_share_one_tail() {
+        for n in [1, 5] {
+            let source = "push constant 1\npush constant 2\neq\n".repeat(n);
+            let asm = translate_source(&source);
+
+            assert_eq!(asm.matches("(__CMP_TRUE)").count(), 1);
+            assert_eq!(asm.matches("(__CMP_FALSE)").count(), 1);
+            assert_eq!(asm.matches("$IF.eq.").count(), 2 * n);
+        }
+
+        let simulator = run_source(
+            "push constant 3\npush constant 3\neq\n".repeat(3).as_str(),
+            &[],
+        );
+        assert_eq!(simulator.ram[256..259], [-1, -1, -1]);
+    }
+
+    #[test]
+    fn logical_and_or_give_canonical_booleans() {
+        let cases = [
+            ("push constant 1\nneg\npush constant 5\nland\n", -1),
+            ("push constant 0\npush constant 5\nland\n", 0),
+            ("push constant 5\npush constant 0\nland\n", 0),
+            ("push constant 0\npush constant 5\nlor\n", -1),
+            ("push constant 0\npush constant 0\nlor\n", 0),
+            // bitwise these would be 0, 4 and 1
+            ("push constant 1\npush constant 2\nland\n", -1),
+            ("push constant 4\npush constant 5\nland\n", -1),
+            ("push constant 1\npush constant 0\nlor\n", -1),
+        ];
+
+        for (source, expected) in cases {
+            let simulator = run_source(source, &[]);
+            assert_eq!(simulator.ram[0], 257, "{source}");
+            assert_eq!(simulator.ram[256], expected, "{source}");
+        }
+    }
+
+    #[test]
+    fn indices_can_be_hex_or_binary() {
+        assert_eq!(parse_index("0x10"), Ok(16));
+        assert_eq!(parse_index("0b101"), Ok(5));
+        assert_eq!(parse_index("12"), Ok(12));
+        assert_eq!(parse_constant("0xFFFF"), Ok(-1));
+        for invalid in ["12x", "0x", "0xG", "0b2", "0x-1", "0b-1", "0x+1"] {
+            assert!(parse_index(invalid).is_err(), "{invalid}");
+            assert!(parse_constant(invalid).is_err(), "{invalid}");
+        }
+
+        let commands = parse_all("push constant 0x10\npush local 0b101\n").unwrap();
+        assert_eq!(commands[0].1.arg2, Some(16));
+        assert_eq!(commands[1].1.arg2, Some(5));
+
+        let error = parse_all("push constant 12x\n").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "line 1: `12x` is not a valid index, expected a decimal, 0x hex or 0b binary number"
+        );
+        assert!(matches!(
+            parse_all("push constant 0x-1\n"),
+            Err(VmError::BadIndex { .. })
+        ));
+    }
+
+    #[test]
+    fn interleave_heads_each_block_with_its_command() {
+        let mut writer = CodeWriter::new(Vec::new());
+        writer.set_interleave(true);
+        writer.write_source_header("push constant 7").unwrap();
+        writer
+            .write_push_pop(CommandType::Push, "constant".to_string(), 7)
+            .unwrap();
+
+        let asm = String::from_utf8(writer.get_mut().clone()).unwrap();
+        let mut lines = asm.lines().skip_while(|line| line.is_empty());
+        assert_eq!(lines.next(), Some("// push constant 7"));
+        // the block under it is indented
+        assert_eq!(lines.next(), Some("    @7"));
+        assert!(lines.all(|line| line.is_empty() || line.starts_with("    ")));
+    }
+
+    #[test]
+    fn scratch_registers_are_configurable() {
+        let source = "function Foo.f 1
+push-range argument 0 2
+pop-range temp 0 2
+push constant 1
+push constant 2
+lt
+pop local 0
+return
+";
+        let registers = |asm: &str| {
+            let mut used: Vec<String> = asm
+                .lines()
+                .filter(|line| line.starts_with("@R"))
+                .map(str::to_string)
+                .collect();
+            used.sort();
+            used.dedup();
+            used
+        };
+
+        let default = translate_source(source);
+        let explicit = translate_with(source, |writer| {
+            writer.set_scratch_registers(13, 14, 15).unwrap();
+        });
+        assert_eq!(explicit, default);
+        assert_eq!(registers(&explicit), ["@R13", "@R14", "@R15"]);
+
+        let swapped = translate_with(source, |writer| {
+            writer.set_scratch_registers(15, 13, 14).unwrap();
+        });
+        assert_ne!(swapped, default);
+        assert_eq!(registers(&swapped), ["@R13", "@R14", "@R15"]);
+
+        let mut writer = CodeWriter::new(Vec::new());
+        assert!(writer.set_scratch_registers(12, 14, 15).is_err());
+        assert!(writer.set_scratch_registers(13, 13, 15).is_err());
+    }
+
+    #[test]
+    fn symbols_that_alias_generated_labels_are_rejected() {
+        for source in [
+            "function Foo.bar$ret.0 0\n",
+            "function Foo.bar 0\ncall Foo.baz$ret 0\n",
+            "function Foo.bar 0\nlabel ret.0\n",
+            "function Foo.bar 0\nlabel IF.eq.0\n",
+        ] {
+            let mut writer = CodeWriter::new(io::sink());
+            let error = translate(source, &mut writer).unwrap_err();
+            assert!(
+                matches!(error, VmError::Translate { .. }),
+                "{source}: {error}"
+            );
+        }
+
+        let mut writer = CodeWriter::new(io::sink());
+        assert!(translate("function Foo.bar 0\nlabel ret\nlabel IF.eq\n", &mut writer).is_ok());
+    }
+
+    #[test]
+    fn diagnose_reports_every_problem() {
+        let source = "function Main.main 0\n  push nowhere 1\npush constant 1\n\tfoo\nreturn\n";
+        let error = |line, column, message: &str| Diagnostic {
+            line,
+            column,
+            severity: Severity::Error,
+            message: message.to_string(),
+        };
+
+        assert_eq!(
+            diagnose(source, "Main"),
+            [
+                error(2, 3, "unknown segment `nowhere`"),
+                error(4, 2, "unrecognized command `foo`"),
+            ]
+        );
+        assert!(diagnose("push constant 1\n", "Main").is_empty());
+    }
+
+    #[test]
+    fn diagnose_reports_an_unclosed_block_comment_once() {
+        assert_eq!(
+            diagnose("push constant 1\n/* open\nadd\n", "Main"),
+            [Diagnostic {
+                line: 2,
+                column: 1,
+                severity: Severity::Error,
+                message: "this `/*` comment is never closed".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn block_comments_are_stripped_mid_line_and_across_lines() {
+        let commands = parse_all("push/*x*/constant 1\n").unwrap();
+        assert_eq!(commands[0].1.to_string(), "push constant 1");
+
+        let source = "push constant 1 /* starts here\nadd\n  still going */ neg\n";
+        let commands = parse_all(source).unwrap();
+        let commands: Vec<_> = commands
+            .iter()
+            .map(|(line, c)| (*line, c.to_string()))
+            .collect();
+        assert_eq!(
+            commands,
+            [(1, "push constant 1".to_string()), (3, "neg".to_string())]
+        );
+    }
+
+    #[test]
+    fn line_comments_inside_block_comments_do_not_end_them() {
+        let commands = parse_all("/* a // b\nadd */ push constant 2\n").unwrap();
+
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0].0, 2);
+        assert_eq!(commands[0].1.to_string(), "push constant 2");
+    }
+
+    #[test]
+    fn unclosed_block_comments_are_an_error() {
+        let mut parser = Parser::new(io::Cursor::new("push constant 1\n/* open\nadd\n"));
+        assert!(parser.advance().unwrap());
+
+        let err = parser.advance().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            err.to_string(),
+            "the `/*` comment on line 2 is never closed"
+        );
+        assert!(parse_all("/* open\npush constant 1\n").is_err());
+    }
+
+    #[test]
+    fn self_check_catches_a_corrupted_helper() {
+        let source = "push constant 1\n//!corrupt\npush constant 2\nadd\n";
+        assert!(assembler::assemble(&translate_source(source)).is_ok());
+
+        // stands in for a helper that writes a malformed instruction
+        let asm = translate_with(source, |writer| {
+            writer.register_directive("corrupt", |_| Ok("D=Q\n".to_string()));
+        });
+        let error = assembler::assemble(&asm).unwrap_err();
+        assert_eq!(error.message, "invalid instruction `D=Q`");
+        assert_eq!(asm.lines().nth(error.line - 1), Some("D=Q"));
+    }
+
+    #[test]
+    fn instructions_are_counted_per_function() {
+        let source = "push constant 1
+pop temp 0
+function Foo.small 0
+return
+function Foo.big 0
+push constant 1
+push constant 2
+add
+push constant 3
+add
+return
+";
+        let mut writer = CodeWriter::new(io::sink());
+        writer.set_namespace("Foo".to_string());
+        translate(source, &mut writer).unwrap();
+        writer.write_end().unwrap();
+
+        let sizes = writer.per_function_sizes();
+        assert!(sizes["Foo.big"] > sizes["Foo.small"]);
+        assert!(sizes[analysis::GLOBAL_SCOPE] > 0);
+        // the end loop is global code too, so every instruction is somewhere
+        assert_eq!(sizes.values().sum::<usize>(), writer.instruction_count());
+    }
+
+    #[test]
+    fn byte_order_mark_is_stripped_from_the_first_line() {
+        let commands = parse_all("\u{feff}function Foo.bar 0\npush constant 1\n").unwrap();
+
+        assert_eq!(commands[0].0, 1);
+        assert_eq!(commands[0].1.to_string(), "function Foo.bar 0");
+        // only at the very start of the file
+        assert!(parse_all("push constant 1\n\u{feff}add\n").is_err());
+    }
+
+    /// a backend that only records what it was asked to write
+    #[derive(Default)]
+    struct Recorder(Vec<String>);
+
+    impl CodeGen for Recorder {
+        fn write_arithmetic(&mut self, command: String) -> Result<(), VmError> {
+            self.0.push(command);
+            Ok(())
+        }
+
+        fn write_push_pop(
+            &mut self,
+            command: CommandType,
+            segment: String,
+            index: i16,
+        ) -> Result<(), VmError> {
+            self.0.push(format!("{command} {segment} {index}"));
+            Ok(())
+        }
+
+        fn write_push_pop_range(
+            &mut self,
+            command: CommandType,
+            segment: String,
+            start: i16,
+            count: i16,
+        ) -> Result<(), VmError> {
+            self.0.push(format!("{command} {segment} {start} {count}"));
+            Ok(())
+        }
+
+        fn write_push_pop_indexed(
+            &mut self,
+            command: CommandType,
+            segment: String,
+        ) -> Result<(), VmError> {
+            self.0.push(format!("{command} {segment}"));
+            Ok(())
+        }
+
+        fn write_directive(&mut self, directive: String) -> Result<(), VmError> {
+            self.0.push(format!("directive {directive}"));
+            Ok(())
+        }
+
+        fn write_debug_print(&mut self) -> Result<(), VmError> {
+            self.0.push("debug-print".to_string());
+            Ok(())
+        }
+
+        fn write_label(&mut self, label_name: String) -> Result<(), VmError> {
+            self.0.push(format!("label {label_name}"));
+            Ok(())
+        }
+
+        fn write_goto(&mut self, label_name: String) -> Result<(), VmError> {
+            self.0.push(format!("goto {label_name}"));
+            Ok(())
+        }
+
+        fn write_if(&mut self, label_name: String) -> Result<(), VmError> {
+            self.0.push(format!("if-goto {label_name}"));
+            Ok(())
+        }
+
+        fn write_function(&mut self, function_name: String, n_locals: i16) -> Result<(), VmError> {
+            self.0.push(format!("function {function_name} {n_locals}"));
+            Ok(())
+        }
+
+        fn write_call(&mut self, function_name: String, n_vars: i16) -> Result<(), VmError> {
+            self.0.push(format!("call {function_name} {n_vars}"));
+            Ok(())
+        }
+
+        fn write_return(&mut self) -> Result<(), VmError> {
+            self.0.push("return".to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn translate_drives_any_backend_in_order() {
+        let source = "function Foo.bar 1
+push argument 0
+push constant 2
+lt
+if-goto SMALL
+call Foo.baz 1
+label SMALL
+return
+";
+        let mut recorder = Recorder::default();
+        translate(source, &mut recorder).unwrap();
+
+        assert_eq!(
+            recorder.0,
+            [
+                "function Foo.bar 1",
+                "push argument 0",
+                "push constant 2",
+                "lt",
+                "if-goto SMALL",
+                "call Foo.baz 1",
+                "label SMALL",
+                "return",
+            ]
+        );
+    }
+
+    #[test]
+    fn resolve_comments_name_the_target() {
+        let source = "push local 3\npush temp 2\npop pointer 1\npush static 4\n";
+        let asm = translate_with(source, |writer| writer.set_resolve_comments(true));
+
+        assert!(asm.contains("// push local 3 -> LCL+3\n"));
+        assert!(asm.contains("// push temp 2 -> R7\n"));
+        assert!(asm.contains("// pop pointer 1 -> THAT\n"));
+        assert!(asm.contains("// push static 4 -> Test.4\n"));
+
+        let asm = translate_source(source);
+        assert!(asm.contains("// push local 3\n") && !asm.contains("->"));
+    }
+
+    #[test]
+    fn comparison_labels_name_the_function_and_op() {
+        let source = "function Foo.bar 0
+push constant 1
+push constant 1
+eq
+push constant 1
+lt
+function Foo.baz 0
+push constant 1
+push constant 1
+eq
+";
+        let asm = translate_source(source);
+
+        assert!(asm.contains("(Foo.bar$IF.eq.0)"));
+        assert!(asm.contains("(Foo.bar$IF.lt.1)"));
+        // numbered within each function
+        assert!(asm.contains("(Foo.baz$IF.eq.0)"));
+    }
+
+    #[test]
+    fn statics_can_skip_the_namespace() {
+        let asm = translate_source("push static 0\n");
+        assert!(asm.starts_with("@Test.0\n"));
+
+        let asm = translate_with("push static 0\npop static 239\n", |writer| {
+            writer.set_static_namespace(false);
+        });
+        assert!(asm.starts_with("@16\n"));
+        assert!(asm.contains("@255\n"));
+        assert!(!asm.contains("Test."));
+
+        // past the end of the statics, whether or not they're namespaced
+        assert!(matches!(
+            parse_all("push static 240\n"),
+            Err(VmError::BadIndex { .. })
+        ));
+        let mut writer = CodeWriter::new(io::sink());
+        writer.set_static_namespace(false);
+        for index in [240, 32760] {
+            let push = writer.write_push_pop(CommandType::Push, "static".to_string(), index);
+            assert!(matches!(push.unwrap_err(), VmError::Translate { .. }));
+        }
+    }
+
+    #[test]
+    fn arithmetic_wraps_around_like_the_alu() {
+        let cases = [
+            ("push constant 32767\npush constant 1\nadd\n", i16::MIN),
+            (
+                "push constant 32767\nneg\npush constant 1\nsub\npush constant 1\nsub\n",
+                i16::MAX,
+            ),
+            (
+                "push constant 32767\nneg\npush constant 1\nsub\nneg\n",
+                i16::MIN,
+            ),
+        ];
+
+        for (source, expected) in cases {
+            let simulator = run_source(source, &[]);
+            assert_eq!(simulator.ram[0], 257, "{source}");
+            assert_eq!(simulator.ram[256], expected, "{source}");
+        }
+    }
+
+    #[test]
+    fn counter_bases_keep_fragments_apart() {
+        let source = "push constant 2\npush constant 2\neq\npush-range temp 0 1\nland\n";
+        let fragment = |base| {
+            let mut writer = CodeWriter::with_counter_base(Vec::new(), base);
+            writer.set_namespace("Main".to_string());
+            translate(source, &mut writer).unwrap();
+            writer
+        };
+        let labels = |writer: &mut CodeWriter<Vec<u8>>| -> Vec<String> {
+            let asm = String::from_utf8(writer.get_mut().clone()).unwrap();
+            let labels = asm.lines().filter(|line| line.starts_with('('));
+            labels.map(str::to_string).collect()
+        };
+
+        let (mut first, mut second) = (fragment(0), fragment(1000));
+        let first_labels = labels(&mut first);
+        assert!(!first_labels.is_empty());
+        assert!(labels(&mut second)
+            .iter()
+            .all(|label| !first_labels.contains(label)));
+
+        let mut writer = CodeWriter::new(Vec::new());
+        writer.append(first).unwrap();
+        writer.append(second).unwrap();
+        writer.write_end().unwrap();
+        let asm = String::from_utf8(writer.get_mut().clone()).unwrap();
+        let simulator = run_asm(&asm, &[(5, 7)]);
+        assert_eq!(simulator.ram[0], 258);
+        assert_eq!(simulator.ram[256..258], [-1, -1]);
+    }
+
+    #[test]
+    fn last_line_without_a_newline_is_translated() {
+        let source = "push constant 7\npush constant 8\nadd";
+        let commands = parse_all(source).unwrap();
+        assert_eq!(commands.len(), 3);
+        assert_eq!(commands[2], (3, parse_all("add\n").unwrap()[0].1.clone()));
+
+        let mut parser = Parser::new(io::Cursor::new(source));
+        for _ in 0..3 {
+            assert!(parser.advance().unwrap());
+        }
+        // the end of the input after it is just the end, not an error
+        assert!(!parser.advance().unwrap());
+        assert!(!parser.advance().unwrap());
+
+        let simulator = run_source(source, &[]);
+        assert_eq!(simulator.ram[0], 257);
+        assert_eq!(simulator.ram[256], 15);
+    }
+
+    #[test]
+    fn goto_can_jump_forward_to_a_later_label() {
+        let source = "function Foo.main 0
+push constant 1
+goto END
+push constant 2
+label END
+push constant 3
+label HALT
+goto HALT
+";
+        let asm = translate_source(source);
+        assert!(asm.contains("@Foo.main$END\n"));
+        assert!(asm.contains("(Foo.main$END)\n"));
+
+        let simulator = run_asm(&asm, &[]);
+        assert_eq!(simulator.ram[0], 258);
+        assert_eq!(simulator.ram[256..258], [1, 3]);
+
+        // outside of any function too
+        let simulator = run_source("goto END\npush constant 2\nlabel END\n", &[]);
+        assert_eq!(simulator.ram[0], 256);
+    }
+
+    #[test]
+    fn indexed_push_and_pop_use_a_runtime_index() {
+        // local[1 + 2] is read, then 99 is stored to local[0 + 1]
+        let source = "push constant 1
+push constant 2
+add
+push-indexed local
+push constant 99
+push constant 0
+push constant 1
+add
+pop-indexed local
+";
+        let lcl = 300;
+        let ram = [(1, lcl), (300, 10), (301, 11), (302, 12), (303, 13)];
+        let simulator = run_source(source, &ram);
+
+        assert_eq!(simulator.ram[0], 257);
+        assert_eq!(simulator.ram[256], 13);
+        assert_eq!(simulator.ram[300..304], [10, 99, 12, 13]);
+    }
+
+    #[test]
+    fn unsigned_constants_keep_their_bits() {
+        let source = "push constant 40000\n";
+        let simulator = run_source(source, &[]);
+        assert_eq!(simulator.ram[256], -25536);
+        assert_eq!(simulator.ram[256] as u16, 40000);
+
+        let lints = analysis::check_unsigned_constants(source);
+        assert_eq!(lints.len(), 1);
+        assert_eq!(
+            lints[0].to_string(),
+            "line 1: `push constant 40000` is over 32767, it will be -25536 on the Hack platform"
+        );
+        assert!(analysis::check_unsigned_constants("push constant 32767\n").is_empty());
+        assert!(parse_all("push constant 65536\n").is_err());
+    }
+
+    #[test]
+    fn label_directive_writes_a_raw_label() {
+        let asm = translate_source("//!label CHECKPOINT\n// label IGNORED\npush constant 1\n");
+
+        assert!(asm.starts_with("(CHECKPOINT)\n"));
+        assert!(!asm.contains("IGNORED"));
+
+        let mut writer = CodeWriter::new(io::sink());
+        assert!(translate("//!label 1bad\n", &mut writer).is_err());
+    }
+
+    /// `--compat reference` output for the course's SimpleAdd, without the bootstrap
+    const SIMPLE_ADD_REFERENCE: &str = "// push constant 7
+@7
+D=A
+@SP
+A=M
+M=D
+@SP
+M=M+1
+// push constant 8
+@8
+D=A
+@SP
+A=M
+M=D
+@SP
+M=M+1
+// add
+@SP
+AM=M-1
+D=M
+@SP
+AM=M-1
+A=M
+D=D+A
+@SP
+A=M
+M=D
+@SP
+M=M+1
+(VMEND)
+@VMEND
+0;JMP
+";
+
+    /// `--compat reference` output for the first comparison of the course's StackTest
+    const STACK_TEST_EQ_REFERENCE: &str = "// push constant 17
+@17
+D=A
+@SP
+A=M
+M=D
+@SP
+M=M+1
+// push constant 17
+@17
+D=A
+@SP
+A=M
+M=D
+@SP
+M=M+1
+// eq
+@SP
+AM=M-1
+D=M
+A=A-1
+D=D-M
+M=-1
+@StackTest.$IF.eq.0
+D;JEQ
+@SP
+A=M-1
+M=0
+(StackTest.$IF.eq.0)
+(VMEND)
+@VMEND
+0;JMP
+";
+
+    #[test]
+    fn reference_compat_matches_the_golden_output() {
+        let compat = |namespace: &str, source: &str| {
+            translate_with(source, |writer| {
+                writer.set_namespace(namespace.to_string());
+                writer.set_reference_compat(true);
+            })
+        };
+
+        let simple_add = compat("SimpleAdd", "push constant 7\npush constant 8\nadd\n");
+        assert_eq!(simple_add, SIMPLE_ADD_REFERENCE);
+        assert_eq!(run_asm(&simple_add, &[]).ram[256], 15);
+
+        let stack_test = compat("StackTest", "push constant 17\npush constant 17\neq\n");
+        assert_eq!(stack_test, STACK_TEST_EQ_REFERENCE);
+        assert_eq!(run_asm(&stack_test, &[]).ram[256], -1);
+    }
+
+    #[test]
+    fn repeated_checkpoint_names_get_distinct_labels() {
+        let asm = translate_with("", |writer| {
+            writer.write_checkpoint("loop").unwrap();
+            writer.write_checkpoint("loop").unwrap();
+            writer.write_checkpoint("done").unwrap();
+        });
+
+        assert!(asm.contains("(__checkpoint.loop)\n"));
+        assert!(asm.contains("(__checkpoint.loop.1)\n"));
+        assert!(asm.contains("(__checkpoint.done)\n"));
+        assert!(!asm.contains("(__checkpoint.done.1)"));
+        assert!(crate::assembler::assemble(&asm).is_ok());
+    }
+
+    #[test]
+    fn invalid_checkpoint_names_are_rejected() {
+        let mut writer = CodeWriter::new(Vec::new());
+        let err = writer.write_checkpoint("has space").unwrap_err();
+        assert!(matches!(err, VmError::Translate { .. }));
+    }
+
+    #[test]
+    fn asm_lines_keep_the_kind_of_each_line() {
+        let source = "push constant 7\nlabel LOOP\nif-goto LOOP\n";
+        let mut writer = CodeWriter::new(AsmLines::new());
+        writer.set_warning_sink(Box::new(io::sink()));
+        writer.set_namespace("Test".to_string());
+        writer.write_source_header("push constant 7").unwrap();
+        translate(source, &mut writer).unwrap();
+        writer.write_end().unwrap();
+        let lines = std::mem::take(writer.get_mut()).into_lines();
+
+        assert_eq!(lines[0], AsmLine::Comment("push constant 7".to_string()));
+        assert_eq!(lines[1], AsmLine::A("7".to_string()));
+        assert_eq!(
+            lines[2],
+            AsmLine::C {
+                dest: Some("D".to_string()),
+                comp: "A".to_string(),
+                jump: None,
+            }
+        );
+        assert!(lines.contains(&AsmLine::Label("Test.$LOOP".to_string())));
+        assert!(lines.contains(&AsmLine::C {
+            dest: None,
+            comp: "D".to_string(),
+            jump: Some("JNE".to_string()),
+        }));
+
+        // the same as parsing the text the writer produces on its own line by line
+        let text = translate_with(source, |writer| {
+            writer.write_source_header("push constant 7").unwrap();
+        });
+        let expected: Vec<AsmLine> = text.lines().flat_map(AsmLine::parse).collect();
+        assert_eq!(lines, expected);
+    }
+
+    #[test]
+    fn asm_line_parse_splits_off_trailing_comments() {
+        assert_eq!(
+            AsmLine::parse("  D = M ; JGT  // jump if positive"),
+            [
+                AsmLine::C {
+                    dest: Some("D".to_string()),
+                    comp: "M".to_string(),
+                    jump: Some("JGT".to_string()),
+                },
+                AsmLine::Comment("jump if positive".to_string()),
+            ]
+        );
+        assert!(AsmLine::parse("   ").is_empty());
+    }
+
+    #[test]
+    fn debug_print_pops_into_the_debug_address() {
+        let source = "push constant 5\npush constant 42\ndebug-print\n";
+
+        let sim = run_source(source, &[]);
+        assert_eq!(sim.ram[DEFAULT_DEBUG_ADDRESS as usize], 42);
+        assert_eq!(sim.ram[0], 257);
+
+        let asm = translate_with(source, |writer| writer.set_debug_address(100).unwrap());
+        let sim = run_asm(&asm, &[]);
+        assert_eq!(sim.ram[100], 42);
+        assert_eq!(sim.ram[DEFAULT_DEBUG_ADDRESS as usize], 0);
+        assert_eq!((sim.ram[0], sim.ram[256]), (257, 5));
+
+        let mut writer = CodeWriter::new(Vec::new());
+        assert!(writer.set_debug_address(-1).is_err());
+    }
+
+    #[test]
+    fn byte_order_mark_does_not_change_the_translation() {
+        let with_bom = translate_source("\u{feff}push constant 7\n");
+
+        assert_eq!(with_bom, translate_source("push constant 7\n"));
+        assert_eq!(run_asm(&with_bom, &[]).ram[256], 7);
+    }
+
+    #[test]
+    fn comparisons_near_the_ends_of_the_range_do_not_overflow() {
+        // (x, y, op, result), x - y overflows 16 bits in every case but the last
+        let cases = [
+            (32767, -2, "gt", -1),
+            (-2, 32767, "gt", 0),
+            (-32768, 32767, "lt", -1),
+            (32767, -32768, "lt", 0),
+            (-32768, 1, "gt", 0),
+            (1, -32768, "gt", -1),
+            (32767, -1, "eq", 0),
+            (-32768, -32768, "eq", -1),
+        ];
+        for mode in ["shared", "inline", "compat"] {
+            for (x, y, op, result) in cases {
+                // -32768 and the negatives can't be pushed as constants, so the stack is
+                // filled in directly
+                let asm = translate_with(op, |writer| match mode {
+                    "inline" => writer.set_inline_compares(true),
+                    "compat" => writer.set_reference_compat(true),
+                    _ => {}
+                });
+                let sim = run_asm(&asm, &[(0, 258), (256, x), (257, y)]);
+
+                assert_eq!(sim.ram[256], result, "{mode}: {x} {op} {y}");
+            }
+        }
+    }
+
+    #[test]
+    fn parse_all_recovering_reports_every_bad_line() {
+        let source = "foo\npush constant 1\npush nowhere 1\nadd\npop\n";
+        let mut parser = Parser::new(io::Cursor::new(source));
+
+        let (commands, errors) = parser.parse_all_recovering();
+        let lines: Vec<usize> = commands.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, [2, 4]);
+        let errors: Vec<(Option<usize>, String)> =
+            errors.iter().map(|e| (e.line(), e.message())).collect();
+        assert_eq!(
+            errors,
+            [
+                (Some(1), "unrecognized command `foo`".to_string()),
+                (Some(3), "unknown segment `nowhere`".to_string()),
+                (Some(5), "`pop` is missing argument 1".to_string()),
+            ]
+        );
+
+        // the free function stops at the first
+        assert_eq!(parse_all(source).unwrap_err().line(), Some(1));
+    }
+
+    #[test]
+    fn writer_errors_are_vm_errors() {
+        let mut writer = CodeWriter::new(Vec::new());
+
+        let err = writer.write_arithmetic("mul".to_string()).unwrap_err();
+        assert!(matches!(err, VmError::UnknownCommand { ref token, line: 0 } if token == "mul"));
+        let err = writer
+            .write_push_pop(CommandType::Push, "nowhere".to_string(), 1)
+            .unwrap_err();
+        assert!(matches!(err, VmError::UnknownSegment { ref name, .. } if name == "nowhere"));
+        let err = writer
+            .write_push_pop(CommandType::Label, "local".to_string(), 1)
+            .unwrap_err();
+        assert!(matches!(err, VmError::Translate { .. }));
+        let err = writer
+            .write_push_pop_range(CommandType::Push, "local".to_string(), 0, 2)
+            .unwrap_err();
+        assert!(matches!(err, VmError::Translate { .. }));
+        // none of them wrote anything
+        assert!(writer.get_mut().is_empty());
+
+        // the writer doesn't know the line, `translate` puts the error on it
+        let err = translate("push constant 1\nlabel a$b\n", &mut writer).unwrap_err();
+        assert_eq!(err.line(), Some(2));
+        assert_eq!(err.at_line(5).line(), Some(2));
+    }
+
+    #[test]
+    fn writer_failures_are_io_errors() {
+        struct Broken;
+
+        impl Write for Broken {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::Error::other("disk full"))
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut writer = CodeWriter::new(Broken);
+        let err = writer.write_return().unwrap_err();
+        assert!(matches!(err, VmError::Io(_)));
+        assert_eq!(err.line(), None);
+    }
+
+    #[test]
+    fn case_insensitive_matches_keywords_only() {
+        let source = "PUSH constant 1\nAdd\nLabel Loop\n";
+        let mut parser = Parser::new(io::Cursor::new(source));
+        parser.set_case_insensitive(true);
+
+        let commands: Vec<String> = parser.map(|result| result.unwrap().1.to_string()).collect();
+        assert_eq!(commands, ["push constant 1", "add", "label Loop"]);
+
+        // segments aren't keywords, and without the setting neither is `PUSH`
+        let mut parser = Parser::new(io::Cursor::new("push CONSTANT 1\n"));
+        parser.set_case_insensitive(true);
+        let err = parser.next().unwrap().unwrap_err();
+        assert!(matches!(err, VmError::UnknownSegment { ref name, .. } if name == "CONSTANT"));
+        assert!(parse_all("PUSH constant 1\n").is_err());
+    }
+
+    #[test]
+    fn byte_offsets_point_at_the_raw_line() {
+        let source = "\u{feff}// note\n\n  push constant 1\nadd\n";
+        let mut parser = Parser::new(io::Cursor::new(source));
+
+        // the comment and blank line are skipped but still counted
+        assert!(parser.advance().unwrap());
+        assert_eq!(parser.byte_offset(), source.find("  push").unwrap());
+        assert!(parser.advance().unwrap());
+        assert_eq!(parser.byte_offset(), source.find("add").unwrap());
+
+        // a first line right after the byte order mark starts after it
+        let mut parser = Parser::new(io::Cursor::new("\u{feff}push constant 1\n"));
+        assert!(parser.advance().unwrap());
+        assert_eq!(parser.byte_offset(), 3);
+    }
+
+    #[test]
+    fn raw_line_for_maps_logical_lines_back() {
+        let source = "// header\npush constant 1\n\n/* block\ncomment */\nadd\n// end\nneg\n";
+        let mut parser = Parser::new(io::Cursor::new(source));
+        while parser.advance().unwrap() {}
+
+        assert_eq!(parser.line, 3);
+        assert_eq!(parser.raw_line_for(1), Some(2));
+        assert_eq!(parser.raw_line_for(2), Some(6));
+        assert_eq!(parser.raw_line_for(3), Some(8));
+        assert_eq!(parser.raw_line_for(0), None);
+        assert_eq!(parser.raw_line_for(4), None);
+    }
+
+    #[test]
+    fn advance_returns_the_peeked_line() {
+        let mut parser = Parser::new(io::Cursor::new("// note\npush constant 1\n\nadd\n"));
+
+        assert_eq!(parser.peek_line().unwrap(), Some("push constant 1"));
+        // peeking again doesn't read any further
+        assert_eq!(parser.peek_line().unwrap(), Some("push constant 1"));
+        assert_eq!((parser.line, parser.line_raw), (0, 0));
+
+        assert!(parser.advance().unwrap());
+        assert_eq!(parser.current_line(), Some("push constant 1"));
+        assert_eq!((parser.line, parser.line_raw), (1, 2));
+        assert_eq!(parser.peek_line().unwrap(), Some("add"));
+        assert!(parser.advance().unwrap());
+        assert_eq!(parser.current_line(), Some("add"));
+        assert_eq!(parser.peek_line().unwrap(), None);
+    }
+
+    #[test]
+    fn has_more_lines_works_before_the_first_advance() {
+        let mut parser = Parser::new(io::Cursor::new("push constant 1\nadd\n"));
+        assert!(parser.has_more_lines());
+
+        let mut lines = Vec::new();
+        while parser.has_more_lines() {
+            assert!(parser.advance().unwrap());
+            lines.push(parser.line_raw);
+        }
+        assert_eq!(lines, [1, 2]);
+
+        // nothing but comments is no lines at all
+        assert!(!Parser::new(io::Cursor::new("// only\n\n")).has_more_lines());
+        assert!(!Parser::new(io::Cursor::new("")).has_more_lines());
+    }
+
+    #[test]
+    fn reset_after_the_end_starts_a_fresh_pass() {
+        let mut parser = Parser::new(io::Cursor::new("push constant 1\nadd\n"));
+        let first: Vec<_> = parser.by_ref().map(Result::unwrap).collect();
+        assert!(!parser.has_more_lines());
+        assert_eq!(parser.peek_line().unwrap(), None);
+
+        parser.reset().unwrap();
+        assert!(parser.has_more_lines());
+        assert_eq!(parser.peek_line().unwrap(), Some("push constant 1"));
+        assert_eq!((parser.line, parser.line_raw), (0, 0));
+        assert_eq!(parser.current_line(), None);
+
+        let second: Vec<_> = parser.by_ref().map(Result::unwrap).collect();
+        assert_eq!(second, first);
+    }
+
+    #[test]
+    fn reset_drops_a_peeked_line() {
+        let mut parser = Parser::new(io::Cursor::new("push constant 1\nadd\n"));
+        parser.advance().unwrap();
+        assert_eq!(parser.peek_line().unwrap(), Some("add"));
+
+        parser.reset().unwrap();
+        assert!(parser.advance().unwrap());
+        assert_eq!(parser.current_line(), Some("push constant 1"));
+        assert_eq!(parser.line_raw, 1);
+    }
+
+    #[test]
+    fn arg2_int_parses_the_second_argument() {
+        let parser_on = |line: &str| {
+            let mut parser = Parser::new(io::Cursor::new(line.to_string()));
+            parser.advance().unwrap();
+            parser
+        };
+
+        assert_eq!(parser_on("push local 0x10").arg2_int().unwrap(), Some(16));
+        // the unsigned constants wrap to the same bits
+        assert_eq!(
+            parser_on("push constant 40000").arg2_int().unwrap(),
+            Some(-25536)
+        );
+        assert_eq!(parser_on("label LOOP").arg2_int().unwrap(), None);
+
+        let err = parser_on("push local abc").arg2_int().unwrap_err();
+        assert!(matches!(err, VmError::BadIndex { ref value, line: 1, .. } if value == "abc"));
+        // only constants take the unsigned range
+        assert!(parser_on("push local 40000").arg2_int().is_err());
+    }
+
+    #[test]
+    fn empty_lines_keep_the_previous_line_number() {
+        let mut parser = Parser::new(io::Cursor::new("push constant 1\n\n// note\nadd\n"));
+        parser.set_keep_empty(true);
+
+        let mut lines = Vec::new();
+        while parser.advance().unwrap() {
+            lines.push((parser.command_type().unwrap(), parser.line, parser.line_raw));
+        }
+        assert_eq!(
+            lines,
+            [
+                (CommandType::Push, 1, 1),
+                (CommandType::Empty, 1, 2),
+                (CommandType::Empty, 1, 3),
+                (CommandType::Arithmetic("add".to_string()), 2, 4),
+            ]
+        );
     }
 }
@@ -1,13 +1,430 @@
 use std::{
+    collections::BTreeMap,
     env,
     fmt::Debug,
-    fs::{read_dir, File},
-    io::{self, BufRead, BufReader, BufWriter, Seek},
+    fs::{self, read_dir, File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Write},
     path::{Path, PathBuf},
-    process,
+    process, thread,
 };
 
-use hack_vm::{CodeWriter, CommandType, Parser};
+use hack_vm::{
+    analysis, assembler, parse_index, simulator::Simulator, CodeWriter, Command, CommandType,
+    CrlfWriter, Parser, VmError,
+};
+
+const USAGE: &str = "Usage: ./hack-vm [options] [input_file.vm | input_dir/]
+       ./hack-vm --repl [--run] [options]
+       ./hack-vm --selftest
+
+Options:
+    -o <file>           write the assembly to <file> instead of ./<input>.asm
+    --mkdir             create the parent directory of the output file if it doesn't exist
+    --deterministic     don't print progress, only errors, warnings and requested reports. the
+                        assembly is always the same for the same inputs, this keeps the
+                        console output stable too
+    --bootstrap-file <file>
+                        write the bootstrap to <file> on its own and leave it out of the
+                        main output, for linking the two together later
+    --library           leave out the bootstrap and the end loop, only writing the translated
+                        functions (and the code they share) for linking into another program
+    --no-bootstrap      leave out the bootstrap, so the program starts at the first command of
+                        its first file. --run starts SP at 256 instead, like the course's tests
+    --self-check        run the generated assembly through the assembler and fail if any line
+                        of it is malformed
+    --emit-sym          assemble the output and write its symbol table next to it as
+                        <output>.sym, one `symbol address` pair per line
+    --listing <file>    write every instruction of the output to <file> with its ROM address
+                        and the VM file, line and command it was translated from
+    --selftest          translate, assemble and run the sample programs built into hack-vm,
+                        checking each one ends with the right values in RAM
+    --repl              read VM commands from stdin one line at a time and print the assembly
+                        for each, with --run the stack is printed after every line too
+    --run               assemble the output and run it in a simulator until it reaches its end
+                        loop, then print the watched RAM (SP and the top of the stack by default)
+    --watch <address>   print RAM[<address>] after --run, can be given more than once
+    --max-cycles <n>    stop --run after <n> instructions (default 1000000)
+    --crlf              end lines with \\r\\n instead of \\n
+    --max-rom <n>       fail if the program needs more than <n> words of ROM (default 32768)
+    --count-only        print how many of each command the input has and exit without
+                        translating
+    --stats             print how many instructions the program and each of its functions take,
+                        largest first
+    --max-function-size <n>
+                        warn about every function that takes more than <n> instructions
+    --list-statics      print each static variable's symbol with the file and line that first
+                        used it
+    --check-asserts     fail if a `// @assert-depth <n>` comment doesn't match the stack depth at
+                        that point
+    --max-depth         print the maximum stack depth reached by each function
+    --entry <function>  start the program at <function> instead of Sys.init
+    --no-sp-init        leave SP as it is instead of setting it to 256 before the entry call
+    --emulator-builtins bootstrap with `call Sys.init 0` as the official emulators expect
+    --no-zero-locals    don't zero a function's locals on entry, only reserve them. unsafe
+                        unless every local is always written before it's read
+    --compat reference  lay the output out like the reference VM translator does (see the README)
+                        and bootstrap with `call Sys.init 0`, for diffing against it
+    --compare-inline-threshold <n>
+                        inline every comparison if the program has fewer than <n> of them,
+                        otherwise have them share the code that pushes their result
+    --validate-only-segment-indices
+                        also fail on constants above 32767. indices outside of their segment's
+                        bounds (pointer 0 or 1, temp 0..=7, never negative) always fail
+    --debug-address <address>
+                        where `debug-print` stores the values it pops (default 24575, the last
+                        word of the screen)
+    --interleave        head the assembly for each command with the VM command itself
+    --no-static-namespace
+                        put `static i` at address 16 + i instead of namespacing it to its file.
+                        for testing only, every file shares the same statics
+    --resolve-comments  name the register or address each push/pop resolved to in its comment
+    --annotate-depth    follow each command with a comment estimating the stack depth after it
+    --exclude <glob>    in directory mode, skip files whose name matches <glob> (`*` matches any
+                        run of characters, `?` any one), can be given more than once
+    --recursive         in directory mode, also translate the files in every subdirectory as part
+                        of the same program
+    --path-namespace    namespace each file by its path from the input directory (`sub.Main`)
+                        instead of its name alone, so same-named files don't share statics
+    --rename <old>=<new>
+                        use the namespace <new> for the file that would otherwise get <old>,
+                        can be given more than once
+    --jobs <n>          translate the files of a directory on <n> threads, the output is the
+                        same as translating them one at a time. ignored with --profile
+    --per-subdir        translate each subdirectory of the input directory as a program of
+                        its own, written to <subdir>.asm
+    --callgraph <file>  write the call graph of the program to <file> in DOT format
+    --profile <file>    wrap every command in (__vm_start.N)/(__vm_end.N) labels and write
+                        the file/line/command for each N to <file>";
+
+/// words of ROM on the Hack platform
+const ROM_SIZE: usize = 32768;
+
+/// a program `--selftest` runs
+struct Sample {
+    name: &'static str,
+    source: &'static str,
+    /// the RAM it should end with, as (address, value)
+    expected: &'static [(usize, i16)],
+}
+
+const SELFTEST_SAMPLES: &[Sample] = &[
+    Sample {
+        name: "SimpleAdd",
+        source: "function Sys.init 0
+push constant 7
+push constant 8
+add
+label END
+goto END",
+        expected: &[(0, 257), (256, 15)],
+    },
+    Sample {
+        name: "Comparisons",
+        source: "function Sys.init 0
+push constant 17
+push constant 17
+eq
+push constant 892
+push constant 891
+lt
+push constant 32767
+push constant 32766
+gt
+label END
+goto END",
+        expected: &[(0, 259), (256, -1), (257, 0), (258, -1)],
+    },
+    Sample {
+        name: "ComparisonOverflow",
+        source: "function Sys.init 0
+push constant 32767
+push constant 2
+neg
+gt
+push constant 2
+neg
+push constant 32767
+gt
+push constant 32767
+neg
+push constant 32767
+lt
+push constant 32767
+push constant 32767
+neg
+lt
+push constant 5
+neg
+push constant 7
+neg
+gt
+push constant 0
+push constant 32767
+neg
+gt
+label END
+goto END",
+        expected: &[
+            (0, 262),
+            (256, -1),
+            (257, 0),
+            (258, -1),
+            (259, 0),
+            (260, -1),
+            (261, -1),
+        ],
+    },
+    Sample {
+        name: "FunctionCall",
+        source: "function Sys.init 0
+push constant 20
+push constant 22
+call Sys.add 2
+label END
+goto END
+function Sys.add 1
+push argument 0
+push argument 1
+add
+pop local 0
+push local 0
+return",
+        expected: &[(256, 42)],
+    },
+];
+
+/// how many instructions `--run` runs for when `--max-cycles` isn't given
+const DEFAULT_MAX_CYCLES: usize = 1_000_000;
+
+#[derive(Default)]
+struct Options {
+    input: String,
+    output: Option<PathBuf>,
+    mkdir: bool,
+    deterministic: bool,
+    bootstrap_file: Option<PathBuf>,
+    library: bool,
+    no_bootstrap: bool,
+    self_check: bool,
+    emit_sym: bool,
+    listing: Option<PathBuf>,
+    run: bool,
+    repl: bool,
+    selftest: bool,
+    watch: Vec<i16>,
+    max_cycles: Option<usize>,
+    crlf: bool,
+    per_subdir: bool,
+    recursive: bool,
+    path_namespace: bool,
+    exclude: Vec<String>,
+    /// derived namespace -> the one to use instead, for `--rename`
+    renames: BTreeMap<String, String>,
+    jobs: Option<usize>,
+    max_rom: Option<usize>,
+    count_only: bool,
+    list_statics: bool,
+    stats: bool,
+    max_function_size: Option<usize>,
+    max_depth: bool,
+    check_asserts: bool,
+    annotate_depth: bool,
+    interleave: bool,
+    debug_address: Option<i16>,
+    strict_indices: bool,
+    compare_inline_threshold: Option<usize>,
+    reference_compat: bool,
+    resolve_comments: bool,
+    no_static_namespace: bool,
+    entry: Option<String>,
+    no_sp_init: bool,
+    emulator_builtins: bool,
+    no_zero_locals: bool,
+    profile: Option<PathBuf>,
+    callgraph: Option<PathBuf>,
+}
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Options {
+        let mut options = Options::default();
+        let mut input = None;
+
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next().unwrap_or_else(|| {
+                    eprintln!("Missing value for {arg}\n{USAGE}");
+                    process::exit(1);
+                })
+            };
+
+            match arg.as_str() {
+                "-o" => options.output = Some(PathBuf::from(value())),
+                "--mkdir" => options.mkdir = true,
+                "--deterministic" => options.deterministic = true,
+                "--bootstrap-file" => options.bootstrap_file = Some(PathBuf::from(value())),
+                "--library" => options.library = true,
+                "--no-bootstrap" => options.no_bootstrap = true,
+                "--self-check" => options.self_check = true,
+                "--emit-sym" => options.emit_sym = true,
+                "--listing" => options.listing = Some(PathBuf::from(value())),
+                "--run" => options.run = true,
+                "--repl" => options.repl = true,
+                "--selftest" => options.selftest = true,
+                "--watch" => {
+                    let arg = value();
+                    options.watch.push(
+                        parse_index(&arg)
+                            .ok()
+                            .filter(|address| *address >= 0)
+                            .unwrap_or_else(|| {
+                                eprintln!("Invalid address for --watch: {arg}");
+                                process::exit(1);
+                            }),
+                    );
+                }
+                "--max-cycles" => {
+                    let arg = value();
+                    options.max_cycles = Some(arg.parse().unwrap_or_else(|_| {
+                        eprintln!("Invalid value for --max-cycles: {arg}");
+                        process::exit(1);
+                    }));
+                }
+                "--crlf" => options.crlf = true,
+                "--per-subdir" => options.per_subdir = true,
+                "--recursive" => options.recursive = true,
+                "--path-namespace" => options.path_namespace = true,
+                "--exclude" => options.exclude.push(value()),
+                "--rename" => {
+                    let arg = value();
+                    let (old, new) = arg
+                        .split_once('=')
+                        .filter(|(_, new)| hack_vm::is_valid_symbol(new))
+                        .unwrap_or_else(|| {
+                            eprintln!(
+                                "Invalid value for --rename: {arg}, expected <old>=<new> where \
+                                 <new> is a valid symbol"
+                            );
+                            process::exit(1);
+                        });
+                    options.renames.insert(old.to_string(), new.to_string());
+                }
+                "--jobs" => {
+                    let arg = value();
+                    options.jobs = Some(arg.parse().ok().filter(|jobs| *jobs > 0).unwrap_or_else(
+                        || {
+                            eprintln!("Invalid value for --jobs: {arg}");
+                            process::exit(1);
+                        },
+                    ));
+                }
+                "--max-rom" => {
+                    let arg = value();
+                    options.max_rom = Some(arg.parse().unwrap_or_else(|_| {
+                        eprintln!("Invalid value for --max-rom: {arg}");
+                        process::exit(1);
+                    }));
+                }
+                "--count-only" => options.count_only = true,
+                "--stats" => options.stats = true,
+                "--max-function-size" => {
+                    let arg = value();
+                    options.max_function_size = Some(arg.parse().unwrap_or_else(|_| {
+                        eprintln!("Invalid value for --max-function-size: {arg}");
+                        process::exit(1);
+                    }));
+                }
+                "--list-statics" => options.list_statics = true,
+                "--max-depth" => options.max_depth = true,
+                "--check-asserts" => options.check_asserts = true,
+                "--annotate-depth" => options.annotate_depth = true,
+                "--interleave" => options.interleave = true,
+                "--debug-address" => {
+                    let arg = value();
+                    options.debug_address = Some(parse_index(&arg).unwrap_or_else(|_| {
+                        eprintln!("Invalid address for --debug-address: {arg}");
+                        process::exit(1);
+                    }));
+                }
+                "--validate-only-segment-indices" => options.strict_indices = true,
+                "--compare-inline-threshold" => {
+                    let arg = value();
+                    options.compare_inline_threshold = Some(arg.parse().unwrap_or_else(|_| {
+                        eprintln!("Invalid value for --compare-inline-threshold: {arg}");
+                        process::exit(1);
+                    }));
+                }
+                "--compat" => {
+                    let arg = value();
+                    if arg != "reference" {
+                        eprintln!(
+                            "Invalid value for --compat: {arg}, the only mode is `reference`"
+                        );
+                        process::exit(1);
+                    }
+                    options.reference_compat = true;
+                }
+                "--resolve-comments" => options.resolve_comments = true,
+                "--no-static-namespace" => options.no_static_namespace = true,
+                "--entry" => options.entry = Some(value()),
+                "--no-sp-init" => options.no_sp_init = true,
+                "--emulator-builtins" => options.emulator_builtins = true,
+                "--no-zero-locals" => options.no_zero_locals = true,
+                "--profile" => options.profile = Some(PathBuf::from(value())),
+                "--callgraph" => options.callgraph = Some(PathBuf::from(value())),
+                "-h" | "--help" => {
+                    println!("{USAGE}");
+                    process::exit(0);
+                }
+                _ if arg.starts_with('-') => {
+                    eprintln!("Unknown option: {arg}\n{USAGE}");
+                    process::exit(1);
+                }
+                _ => input = Some(arg),
+            }
+        }
+
+        options.input = match input {
+            Some(input) => input,
+            None if options.repl || options.selftest => String::new(),
+            None => {
+                println!("{USAGE}");
+                process::exit(0);
+            }
+        };
+        if options.recursive && options.per_subdir {
+            eprintln!("--recursive and --per-subdir can't be used together\n{USAGE}");
+            process::exit(1);
+        }
+        if options.no_bootstrap && options.bootstrap_file.is_some() {
+            eprintln!("--no-bootstrap and --bootstrap-file can't be used together\n{USAGE}");
+            process::exit(1);
+        }
+        if options.library && (options.run || options.repl) {
+            eprintln!("--library has no entry point, so it can't be run\n{USAGE}");
+            process::exit(1);
+        }
+        options
+    }
+}
+
+/// sidecar written for `--profile`, maps the N of each `(__vm_start.N)` label back to the VM
+/// command it wraps
+struct Profile {
+    out: BufWriter<File>,
+    count: usize,
+}
+impl Profile {
+    fn create(path: &Path) -> Profile {
+        let file = File::create(path).unwrap_or_else(|e| {
+            eprintln!("Error while creating profile file: {}", e);
+            process::exit(1);
+        });
+
+        Profile {
+            out: BufWriter::new(file),
+            count: 0,
+        }
+    }
+}
 
 struct FileInfo {
     path: PathBuf,
@@ -42,60 +459,251 @@ impl FileInfo {
         Self::gen_namespace_raw(&mut self.path.clone())
     }
 
-    fn create_output_file(input_path: PathBuf) -> FileInfo {
-        let name = input_path.with_extension("asm");
-        let path = PathBuf::from(name.file_name().unwrap_or_else(|| {
+    /// the namespace for `--path-namespace`: the path from `root` without the extension, with
+    /// each directory joined by a `.` and anything the assembler wouldn't accept replaced by `_`
+    fn gen_path_namespace(&self, root: &Path) -> String {
+        let relative = self.path.strip_prefix(root).unwrap_or(&self.path);
+        let relative = relative.with_extension("");
+
+        relative
+            .components()
+            .map(|component| {
+                component
+                    .as_os_str()
+                    .to_string_lossy()
+                    .chars()
+                    .map(|c| match c {
+                        c if c.is_ascii_alphanumeric() || "_$:".contains(c) => c,
+                        _ => '_',
+                    })
+                    .collect::<String>()
+            })
+            .collect::<Vec<_>>()
+            .join(".")
+    }
+
+    /// `input.asm` in the working directory, the file name alone is kept so a directory input
+    /// still gets its output next to where the translator was run
+    fn default_output_path(input_path: &Path) -> PathBuf {
+        let name = PathBuf::from(input_path.file_name().unwrap_or_default()).with_extension("asm");
+        PathBuf::from(name.file_name().unwrap_or_else(|| {
             eprintln!("Invalid path");
             process::exit(1);
-        }));
+        }))
+    }
 
-        let file = File::create(&path).unwrap_or_else(|e| {
-            eprintln!("Error while creating output file: {}", e);
-            process::exit(1);
-        });
+    /// always truncates, so output left behind by a run that died half way through never leaks
+    /// into the next one
+    fn create_output_file(path: PathBuf) -> FileInfo {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap_or_else(|e| {
+                eprintln!("Error while creating output file: {}", e);
+                process::exit(1);
+            });
 
-        let name = String::from(name.as_os_str().to_string_lossy());
+        let name = String::from(path.as_os_str().to_string_lossy());
 
         FileInfo { path, file, name }
     }
 }
 
-fn translate_file<W: BufRead + Seek + Debug>(
-    writer: &mut CodeWriter<BufWriter<File>>,
+/// whether `name` matches the glob `pattern`, where `*` matches any run of characters and `?`
+/// matches exactly one
+fn glob_match(pattern: &str, name: &str) -> bool {
+    match pattern.chars().next() {
+        None => name.is_empty(),
+        Some('*') => {
+            let rest = &pattern[1..];
+            name.char_indices()
+                .map(|(i, _)| i)
+                .chain([name.len()])
+                .any(|i| glob_match(rest, &name[i..]))
+        }
+        Some(c) => {
+            let mut name_chars = name.chars();
+            match name_chars.next() {
+                Some(n) if c == '?' || c == n => {
+                    glob_match(&pattern[c.len_utf8()..], name_chars.as_str())
+                }
+                _ => false,
+            }
+        }
+    }
+}
+
+/// the `.vm` files to translate in name order, a directory is read one level deep (or all the way
+/// down with `--recursive`), skipping any whose name matches an `--exclude` glob. the order
+/// `read_dir` gives differs between filesystems, which would shuffle the output
+fn collect_inputs(input_path: &Path, options: &Options) -> Vec<PathBuf> {
+    if !input_path.is_dir() {
+        return vec![input_path.to_path_buf()];
+    }
+
+    let mut inputs: Vec<PathBuf> = read_dir(input_path)
+        .unwrap_or_else(|e| {
+            eprintln!("ERROR: {e}");
+            process::exit(2);
+        })
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| !path.is_dir() && path.extension().is_none_or(|x| x == "vm"))
+        .filter(|path| {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            !options
+                .exclude
+                .iter()
+                .any(|pattern| glob_match(pattern, &name))
+        })
+        .collect();
+    inputs.sort();
+
+    if options.recursive {
+        for subdir in collect_subdirs(input_path) {
+            inputs.extend(collect_inputs(&subdir, options));
+        }
+    }
+    inputs
+}
+
+/// the absolute form of `path`, which doesn't have to exist yet
+fn resolve_path(path: &Path) -> PathBuf {
+    if let Ok(path) = path.canonicalize() {
+        return path;
+    }
+
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    parent
+        .canonicalize()
+        .map(|parent| parent.join(path.file_name().unwrap_or_default()))
+        .unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// exits if writing to `output` would truncate one of the inputs before it's been read
+fn check_output_path(output: &Path, inputs: &[PathBuf]) {
+    let resolved_output = resolve_path(output);
+
+    if let Some(input) = inputs.iter().find(|x| resolve_path(x) == resolved_output) {
+        eprintln!(
+            "ERROR: output file `{}` is also an input file `{}`",
+            output.display(),
+            input.display()
+        );
+        process::exit(1);
+    }
+}
+
+/// exits if the directory `output` goes in is missing, unless `mkdir` is set in which case it's
+/// created
+fn check_output_dir(output: &Path, mkdir: bool) {
+    let dir = match output.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() && !dir.is_dir() => dir,
+        _ => return,
+    };
+
+    if !mkdir {
+        eprintln!(
+            "ERROR: output directory `{}` does not exist; pass --mkdir to create",
+            dir.display()
+        );
+        process::exit(1);
+    }
+    fs::create_dir_all(dir).unwrap_or_else(|e| {
+        eprintln!(
+            "ERROR: couldn't create output directory `{}`: {e}",
+            dir.display()
+        );
+        process::exit(1);
+    });
+}
+
+/// removes whatever has been written to `output_path` so far and exits, a translation that
+/// fails part way through never leaves a partial program behind
+fn abandon_output(output_path: &Path) -> ! {
+    let _ = fs::remove_file(output_path);
+    process::exit(1);
+}
+
+/// translates everything `parser` has left into `writer`, returning the commands it saw along
+/// with their line numbers for any analysis passes, and the instruction each one starts at. on
+/// an error the program being written to `output_path` is abandoned
+fn translate_file<W: BufRead + Debug, O: Write>(
+    writer: &mut CodeWriter<O>,
     parser: &mut Parser<W>,
-) {
-    parser.advance().expect("the parser should be able to advance the first line if everything is functioning as expected");
+    file_name: &str,
+    output_path: &Path,
+    options: &Options,
+    mut profile: Option<&mut Profile>,
+) -> (Vec<(usize, Command)>, Vec<usize>) {
+    let mut commands = Vec::new();
+    let mut addresses = Vec::new();
+    let mut depth = analysis::DepthTracker::new();
+    let mut failed = false;
+
     while parser.has_more_lines() {
-        let command_type = parser.command_type();
-
-        let arg1 = if command_type != CommandType::Return {
-            parser.arg1().unwrap_or_else(|| {
-                eprintln!(
-                    "Error extracting arg1 from line {}\n{:#?}",
-                    parser.line_raw, parser
-                );
-                process::exit(1);
-            })
-        } else {
-            String::new()
+        parser.advance().unwrap_or_else(|e| {
+            eprintln!("ERROR: {file_name} {e}");
+            abandon_output(output_path);
+        });
+        // keep going past a bad line so every one in the file is reported
+        let command = match parser.parse() {
+            Ok(command) => command,
+            Err(e) => {
+                eprintln!("ERROR: {file_name} {e}");
+                failed = true;
+                continue;
+            }
         };
+        let command_type = command.command_type.clone();
+        addresses.push(writer.instruction_count());
 
-        let grab_arg2 = || {
-            parser
-                .arg2()
-                .and_then(|x| x.parse::<i16>().ok())
-                .unwrap_or_else(|| {
-                    eprintln!(
-                        "Error extracting arg 2 from line {}\n{:#?}",
-                        parser.line_raw, parser
-                    );
-                    process::exit(1)
-                })
+        // `Parser::parse` has already checked the command has every argument it needs
+        let arg1 = command.arg1.clone().unwrap_or_default();
+        let arg2 = command.arg2.unwrap_or_default();
+        let arg3 = command.arg3.unwrap_or_default();
+
+        let line = parser.line_raw;
+        let output_write_error = |e: VmError| {
+            match e {
+                VmError::Io(e) => eprintln!("Error writing to output file: {}", e),
+                // the command itself couldn't be translated
+                e => eprintln!("ERROR: {file_name} {}", e.at_line(line)),
+            }
+            abandon_output(output_path);
         };
 
-        let output_write_error = |e: io::Error| {
-            eprintln!("Error writing to output file: {}", e);
-            process::exit(1);
+        if options.interleave && command_type != CommandType::Empty {
+            writer
+                .write_source_header(&command.to_string())
+                .unwrap_or_else(output_write_error);
+        }
+
+        let profile_index = match profile.as_deref_mut() {
+            Some(profile) if command_type != CommandType::Empty => {
+                let n = profile.count;
+                profile.count += 1;
+
+                writeln!(
+                    profile.out,
+                    "{n}\t{file_name}\t{}\t{command}",
+                    parser.line_raw
+                )
+                .unwrap_or_else(|e| {
+                    eprintln!("Error writing to profile file: {}", e);
+                    process::exit(1);
+                });
+                writer
+                    .write_profile_start(n)
+                    .unwrap_or_else(output_write_error);
+                Some(n)
+            }
+            _ => None,
         };
 
         match &command_type {
@@ -105,9 +713,8 @@ fn translate_file<W: BufRead + Seek + Debug>(
                     .unwrap_or_else(output_write_error);
             }
             CommandType::Push | CommandType::Pop => {
-                let index = grab_arg2();
                 writer
-                    .write_push_pop(command_type, arg1, index)
+                    .write_push_pop(command_type, arg1, arg2)
                     .unwrap_or_else(output_write_error);
             }
             CommandType::Label => {
@@ -120,80 +727,806 @@ fn translate_file<W: BufRead + Seek + Debug>(
                 writer.write_if(arg1).unwrap_or_else(output_write_error);
             }
             CommandType::Function => {
-                let n_vars = grab_arg2();
                 writer
-                    .write_function(arg1, n_vars)
+                    .write_function(arg1, arg2)
                     .unwrap_or_else(output_write_error);
             }
             CommandType::Call => {
-                let n_vars = grab_arg2();
                 writer
-                    .write_call(arg1, n_vars)
+                    .write_call(arg1, arg2)
                     .unwrap_or_else(output_write_error);
             }
             CommandType::Return => {
                 writer.write_return().unwrap_or_else(output_write_error);
             }
-            _ => {
-                println!();
-                todo!()
+            CommandType::PushRange | CommandType::PopRange => {
+                writer
+                    .write_push_pop_range(command_type, arg1, arg2, arg3)
+                    .unwrap_or_else(output_write_error);
+            }
+            CommandType::PushIndexed | CommandType::PopIndexed => {
+                writer
+                    .write_push_pop_indexed(command_type, arg1)
+                    .unwrap_or_else(output_write_error);
+            }
+            CommandType::Directive => {
+                writer
+                    .write_directive(arg1)
+                    .unwrap_or_else(output_write_error);
             }
+            CommandType::DebugPrint => {
+                writer
+                    .write_debug_print()
+                    .unwrap_or_else(output_write_error);
+            }
+            CommandType::Empty => {}
+        }
+
+        if let Some(n) = profile_index {
+            writer
+                .write_profile_end(n)
+                .unwrap_or_else(output_write_error);
+        }
+        if options.annotate_depth && command.command_type != CommandType::Empty {
+            let depth = match depth.step(&command) {
+                Some(depth) => depth.to_string(),
+                None => "?".to_string(),
+            };
+            writer
+                .write_comment(&format!("depth: {depth}"))
+                .unwrap_or_else(output_write_error);
+        }
+        commands.push((parser.line_raw, command));
+    }
+
+    if failed {
+        abandon_output(output_path);
+    }
+    if !options.deterministic {
+        let namespace = writer.get_namespace();
+        println!("Finished {namespace}");
+    }
+
+    (commands, addresses)
+}
+
+fn print_lints(file_name: &str, lints: Vec<analysis::Lint>) {
+    for lint in lints {
+        eprintln!("Warning: {file_name} {lint}");
+    }
+}
+
+fn print_max_depth(commands: &[(usize, Command)]) {
+    for (function, depth) in analysis::max_stack_depth(commands) {
+        println!("{function}: max stack depth {depth}");
+    }
+}
+
+/// creates the file at `path` for a writer to write assembly to
+fn open_output(path: PathBuf, options: &Options) -> Box<dyn Write> {
+    let output_file = FileInfo::create_output_file(path);
+    if options.crlf {
+        Box::new(CrlfWriter::new(BufWriter::new(output_file.file)))
+    } else {
+        Box::new(BufWriter::new(output_file.file))
+    }
+}
+
+/// assembles the program written to `output_path` and writes its symbol table to the same path
+/// with a `.sym` extension, sorted by address, for `--emit-sym`
+fn write_symbol_file(output_path: &Path) {
+    let asm = fs::read_to_string(output_path).unwrap_or_else(|e| {
+        eprintln!("Error while reading output file: {}", e);
+        process::exit(1);
+    });
+    let (_, symbols) = assembler::assemble_with_symbols(&asm).unwrap_or_else(|e| {
+        eprintln!("ERROR: couldn't assemble the output, {e}");
+        process::exit(1);
+    });
+
+    let mut symbols: Vec<_> = symbols.into_iter().collect();
+    symbols.sort_by_key(|(_, address)| *address);
+    let table: String = symbols
+        .iter()
+        .map(|(symbol, address)| format!("{symbol} {address}\n"))
+        .collect();
+    fs::write(output_path.with_extension("sym"), table).unwrap_or_else(|e| {
+        eprintln!("Error writing symbol file: {}", e);
+        process::exit(1);
+    });
+}
+
+/// writes the listing of the program written to `output_path` for `--listing`: every label, and
+/// every instruction with its address and the VM command from `origins` it belongs to, the last
+/// one starting at or before it. anything before the first command is the bootstrap and anything
+/// from `end_address` on is the end loop and the shared code after it
+fn write_listing(
+    output_path: &Path,
+    listing_path: &Path,
+    origins: &[(usize, String, usize, String)],
+    end_address: usize,
+) {
+    let asm = fs::read_to_string(output_path).unwrap_or_else(|e| {
+        eprintln!("Error while reading output file: {}", e);
+        process::exit(1);
+    });
+
+    let mut listing = String::new();
+    let mut address = 0;
+    for line in asm.lines() {
+        let code = line.split("//").next().unwrap_or_default().trim();
+        if code.is_empty() {
+            continue;
         }
+        if code.starts_with('(') {
+            listing.push_str(&format!("{:5}  {code}\n", ""));
+            continue;
+        }
+
+        let origin = match origins.partition_point(|(start, ..)| *start <= address) {
+            _ if address >= end_address => "<end>".to_string(),
+            0 => "<bootstrap>".to_string(),
+            i => {
+                let (_, file_name, line, command) = &origins[i - 1];
+                format!("{file_name}:{line}  {command}")
+            }
+        };
+        listing.push_str(&format!("{address:5}  {code:<20}  {origin}\n"));
+        address += 1;
+    }
+
+    fs::write(listing_path, listing).unwrap_or_else(|e| {
+        eprintln!("Error writing listing file: {}", e);
+        process::exit(1);
+    });
+}
+
+/// assembles the program written to `output_path` (after the bootstrap file, if it has one) and
+/// runs it, for `--run`
+fn run_program(output_path: &Path, options: &Options) {
+    let read = |path: &Path| {
+        fs::read_to_string(path).unwrap_or_else(|e| {
+            eprintln!("Error while reading {}: {e}", path.display());
+            process::exit(1);
+        })
+    };
+    let mut asm = options
+        .bootstrap_file
+        .as_deref()
+        .map(read)
+        .unwrap_or_default();
+    asm.push_str(&read(output_path));
+
+    let rom = assembler::assemble(&asm).unwrap_or_else(|e| {
+        eprintln!("ERROR: couldn't assemble the output, {e}");
+        process::exit(1);
+    });
+    let mut simulator = Simulator::new(rom);
+    if options.no_bootstrap {
+        simulator.ram[0] = 256;
+    }
+    let cycles = simulator.run(options.max_cycles.unwrap_or(DEFAULT_MAX_CYCLES));
+
+    if simulator.halted() {
+        println!("halted after {cycles} cycles");
+    } else {
+        println!("stopped after {cycles} cycles without halting");
+    }
+
+    let watch = if options.watch.is_empty() {
+        vec![0, simulator.ram[0].wrapping_sub(1)]
+    } else {
+        options.watch.clone()
+    };
+    for address in watch {
+        let value = simulator
+            .ram
+            .get(address as u16 as usize)
+            .copied()
+            .unwrap_or(0);
+        println!("RAM[{address}] = {value}");
+    }
+}
+
+fn print_stats(size: usize, function_sizes: &BTreeMap<String, usize>) {
+    println!("instructions: {size}");
+
+    let mut function_sizes: Vec<_> = function_sizes.iter().collect();
+    function_sizes.sort_by_key(|(_, size)| std::cmp::Reverse(**size));
+    for (function, size) in function_sizes {
+        println!("    {function}: {size}");
+    }
+}
 
-        parser.advance().unwrap_or_else(|_| {
-            let namespace = writer.get_namespace();
-            println!("Finished {namespace}");
-            return;
+/// applies the options that change the generated code to `writer`
+fn configure_writer<O: Write>(writer: &mut CodeWriter<O>, options: &Options) {
+    writer.set_emulator_builtins(options.emulator_builtins || options.reference_compat);
+    writer.set_zero_locals(!options.no_zero_locals);
+    writer.set_interleave(options.interleave);
+    writer.set_strict_indices(options.strict_indices);
+    writer.set_reference_compat(options.reference_compat);
+    writer.set_resolve_comments(options.resolve_comments);
+    writer.set_static_namespace(!options.no_static_namespace);
+    if let Some(entry) = &options.entry {
+        writer.set_entry_point(entry).unwrap_or_else(|e| {
+            eprintln!("ERROR: {e}");
+            process::exit(1);
+        });
+    }
+    if let Some(address) = options.debug_address {
+        writer.set_debug_address(address).unwrap_or_else(|e| {
+            eprintln!("ERROR: {e}");
+            process::exit(1);
         });
     }
 }
 
-fn main() {
-    let mut args = env::args();
-    let input_arg = args.nth(1).unwrap_or_else(|| {
-        println!("Usage: ./hack-vm [input_file.vm | input_dir/]");
-        process::exit(0);
+/// writes the code that sets up SP and calls the entry point
+fn write_bootstrap<O: Write>(writer: &mut CodeWriter<O>, options: &Options) {
+    let sp_init = if options.no_sp_init {
+        Ok(())
+    } else {
+        writer.write_sp_init()
+    };
+    sp_init
+        .and_then(|_| writer.write_entry_call())
+        .unwrap_or_else(|e| {
+            eprintln!("ERROR: {e}");
+            process::exit(2);
+        });
+}
+
+/// writes the bootstrap on its own to `path`, for `--bootstrap-file`
+fn write_bootstrap_file(path: &Path, options: &Options) {
+    let mut writer = CodeWriter::new(open_output(path.to_path_buf(), options));
+    configure_writer(&mut writer, options);
+    write_bootstrap(&mut writer, options);
+    writer.flush().unwrap_or_else(|e| {
+        eprintln!("Error writing to bootstrap file: {}", e);
+        process::exit(1);
     });
-    let input_path = Path::new(&input_arg).to_path_buf();
-    let output_file =
-        FileInfo::create_output_file(PathBuf::from(input_path.file_name().unwrap_or_default()));
-    let mut writer = CodeWriter::new(BufWriter::new(output_file.file));
-
-    writer.write_init().unwrap_or_else(|e| {
-        eprintln!("ERROR: {e}");
-        process::exit(2);
+}
+
+/// a file that's been translated, for the checks that run once it's done
+struct TranslatedFile {
+    path: PathBuf,
+    name: String,
+    namespace: String,
+    commands: Vec<(usize, Command)>,
+    /// the address of the instruction each of `commands` starts at
+    addresses: Vec<usize>,
+}
+
+/// translates the file at `path` into `writer` under its own namespace, as part of the program
+/// written to `output_path`
+fn translate_input<O: Write>(
+    writer: &mut CodeWriter<O>,
+    path: PathBuf,
+    output_path: &Path,
+    options: &Options,
+    profile: Option<&mut Profile>,
+) -> TranslatedFile {
+    let file = FileInfo::new(path);
+    let mut parser = Parser::new(BufReader::new(&file.file));
+
+    let namespace = if options.path_namespace && Path::new(&options.input).is_dir() {
+        file.gen_path_namespace(Path::new(&options.input))
+    } else {
+        file.gen_namespace()
+    };
+    match options.renames.get(&namespace) {
+        Some(renamed) => writer.set_namespace(renamed.clone()),
+        None => writer.set_namespace(namespace),
+    }
+    if Path::new(&options.input).is_dir() && !options.deterministic {
+        println!("Translating new file: {}", &file.name);
+    }
+    let (commands, addresses) = translate_file(
+        writer,
+        &mut parser,
+        &file.name,
+        output_path,
+        options,
+        profile,
+    );
+
+    TranslatedFile {
+        path: file.path,
+        name: file.name,
+        namespace: writer.get_namespace().clone(),
+        commands,
+        addresses,
+    }
+}
+
+/// `translate_input` for every file in `inputs`, split between `jobs` threads. each thread
+/// translates into a buffer of its own and the buffers are appended to `writer` in the order of
+/// `inputs`, since the label counters start over for every file that's the same output as
+/// translating them one at a time
+fn translate_parallel<O: Write>(
+    writer: &mut CodeWriter<O>,
+    inputs: Vec<PathBuf>,
+    output_path: &Path,
+    options: &Options,
+    jobs: usize,
+) -> Vec<TranslatedFile> {
+    let chunk_size = inputs.len().div_ceil(jobs).max(1);
+    let inline_compares = writer.inline_compares();
+
+    let fragments: Vec<_> = thread::scope(|scope| {
+        let threads: Vec<_> = inputs
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    let mut fragment = CodeWriter::new(Vec::new());
+                    configure_writer(&mut fragment, options);
+                    fragment.set_inline_compares(inline_compares);
+                    let translated: Vec<_> = chunk
+                        .iter()
+                        .map(|path| {
+                            translate_input(&mut fragment, path.clone(), output_path, options, None)
+                        })
+                        .collect();
+                    (fragment, translated)
+                })
+            })
+            .collect();
+
+        threads
+            .into_iter()
+            .map(|thread| thread.join().expect("a translation thread panicked"))
+            .collect()
     });
-    let mut parser: Parser<BufReader<&File>>;
 
-    if input_path.is_dir() {
-        for entry in read_dir(&input_path)
-            .unwrap_or_else(|e| {
-                eprintln!("ERROR: {e}");
-                process::exit(2);
+    let mut translated = Vec::new();
+    for (fragment, files) in fragments {
+        // the fragment's addresses count from its own start
+        let offset = writer.instruction_count();
+        writer.append(fragment).unwrap_or_else(|e| {
+            eprintln!("Error writing to output file: {}", e);
+            process::exit(1);
+        });
+        translated.extend(files.into_iter().map(|mut file| {
+            file.addresses
+                .iter_mut()
+                .for_each(|address| *address += offset);
+            file
+        }));
+    }
+    translated
+}
+
+/// translates `inputs` into a single program written to `output_path`, returning every command
+/// translated
+fn translate_program(
+    inputs: Vec<PathBuf>,
+    output_path: PathBuf,
+    options: &Options,
+    mut profile: Option<&mut Profile>,
+) -> Vec<(usize, Command)> {
+    let mut program = Vec::new();
+    // static symbol -> (file, line) of its first use
+    let mut statics = BTreeMap::new();
+    // function name -> (file, line) of its definition
+    let mut functions = BTreeMap::new();
+    // worked out before the output is created, so inputs that don't parse leave none behind
+    let inline_compares = options.compare_inline_threshold.map(|threshold| {
+        let counts = analysis::count_commands(&parse_inputs(&inputs));
+        let compares: usize = ["eq", "gt", "lt"]
+            .iter()
+            .filter_map(|op| counts.get(*op))
+            .sum();
+        compares < threshold
+    });
+    let mut writer = CodeWriter::new(open_output(output_path.clone(), options));
+    configure_writer(&mut writer, options);
+    if let Some(inline_compares) = inline_compares {
+        writer.set_inline_compares(inline_compares);
+    }
+    if options.bootstrap_file.is_none() && !options.library && !options.no_bootstrap {
+        write_bootstrap(&mut writer, options);
+    }
+
+    let jobs = options.jobs.unwrap_or(1);
+    let translated = if jobs > 1 && profile.is_none() {
+        translate_parallel(&mut writer, inputs, &output_path, options, jobs)
+    } else {
+        inputs
+            .into_iter()
+            .map(|path| {
+                translate_input(
+                    &mut writer,
+                    path,
+                    &output_path,
+                    options,
+                    profile.as_deref_mut(),
+                )
             })
-            .flatten()
-        {
-            if entry.path().is_dir() || entry.path().extension().is_some_and(|x| x != "vm") {
-                continue;
+            .collect()
+    };
+
+    // (address, file, line, command) for each command, in address order, for `--listing`
+    let mut origins = Vec::new();
+    let mut failed_asserts = false;
+    for file in translated {
+        let source = fs::read_to_string(&file.path).unwrap_or_else(|e| {
+            eprintln!("Error while reading input file: {}", e);
+            process::exit(1);
+        });
+        if options.check_asserts {
+            for lint in analysis::check_depth_asserts(&source, &file.commands) {
+                eprintln!("ERROR: {} {lint}", file.name);
+                failed_asserts = true;
             }
+        }
+        print_lints(&file.name, analysis::check_fallthrough(&file.commands));
+        print_lints(&file.name, analysis::check_call_args(&file.commands));
+        print_lints(&file.name, analysis::check_unsigned_constants(&source));
+        if options.max_depth {
+            print_max_depth(&file.commands);
+        }
+        for (index, line) in analysis::static_uses(&file.commands) {
+            let symbol = format!("{}.{index}", file.namespace);
+            statics.entry(symbol).or_insert((file.name.clone(), line));
+        }
+        for (function, line) in analysis::function_definitions(&file.commands) {
+            functions
+                .entry(function)
+                .or_insert((file.name.clone(), line));
+        }
+        if options.listing.is_some() {
+            origins.extend(file.addresses.iter().zip(&file.commands).map(
+                |(address, (line, command))| {
+                    (*address, file.name.clone(), *line, command.to_string())
+                },
+            ));
+        }
+        program.extend(file.commands);
+    }
 
-            let file = FileInfo::new(entry.path());
-            parser = Parser::new(BufReader::new(&file.file));
+    if failed_asserts {
+        let _ = fs::remove_file(&output_path);
+        process::exit(1);
+    }
+
+    // a function named like a static symbol (`function Main.0 0` next to Main.vm's `static 0`)
+    // gets the same assembly symbol, which the assembler would silently merge into one
+    let collisions: Vec<_> = functions
+        .iter()
+        .filter_map(|(symbol, function)| Some((symbol, function, statics.get(symbol)?)))
+        .collect();
+    for (symbol, (function_file, function_line), (static_file, static_line)) in &collisions {
+        eprintln!(
+            "ERROR: function `{symbol}` ({function_file} line {function_line}) has the same \
+             symbol as a static variable ({static_file} line {static_line})"
+        );
+    }
+    if !collisions.is_empty() {
+        let _ = fs::remove_file(&output_path);
+        process::exit(1);
+    }
 
-            writer.set_namespace(file.gen_namespace());
-            println!("Translating new file: {}", &file.name);
-            translate_file(&mut writer, &mut parser);
+    if options.list_statics {
+        for (symbol, (file_name, line)) in statics {
+            println!("{symbol}: {file_name} line {line}");
         }
+    }
+
+    let end_address = writer.instruction_count();
+    let end = if options.library {
+        writer.write_shared_code()
     } else {
-        let input_file = FileInfo::new(input_path.clone());
-        let namespace = input_file.gen_namespace();
-        let input_file = BufReader::new(&input_file.file);
+        writer.write_end()
+    };
+    end.and_then(|_| Ok(writer.flush()?)).unwrap_or_else(|e| {
+        eprintln!("Error writing to output file: {}", e);
+        process::exit(1);
+    });
+
+    let max_rom = options.max_rom.unwrap_or(ROM_SIZE);
+    let size = writer.instruction_count();
+    if options.stats {
+        print_stats(size, writer.per_function_sizes());
+    }
+    if let Some(max_function_size) = options.max_function_size {
+        let oversized = writer
+            .per_function_sizes()
+            .iter()
+            .filter(|(function, size)| {
+                function.as_str() != analysis::GLOBAL_SCOPE && **size > max_function_size
+            });
+        for (function, size) in oversized {
+            eprintln!(
+                "Warning: function `{function}` is {size} instructions, over the limit of \
+                 {max_function_size}"
+            );
+        }
+    }
+    if size > max_rom {
+        // an unloadable program is worse than none at all
+        let _ = fs::remove_file(&output_path);
+        eprintln!(
+            "ERROR: the program needs {size} words of ROM, {} more than the {max_rom} available",
+            size - max_rom
+        );
+        process::exit(1);
+    }
+
+    if options.self_check {
+        let asm = fs::read_to_string(&output_path).unwrap_or_else(|e| {
+            eprintln!("Error while reading output file: {}", e);
+            process::exit(1);
+        });
+        if let Err(e) = assembler::assemble(&asm) {
+            let _ = fs::remove_file(&output_path);
+            eprintln!("ERROR: self-check failed, generated {e}");
+            process::exit(1);
+        }
+    }
+    if options.emit_sym {
+        write_symbol_file(&output_path);
+    }
+    if let Some(listing_path) = &options.listing {
+        write_listing(&output_path, listing_path, &origins, end_address);
+    }
+    if options.run {
+        let entry = options.entry.as_deref().unwrap_or("Sys.init");
+        if !options.no_bootstrap && !functions.contains_key(entry) {
+            eprintln!("Warning: `{entry}` isn't defined, the bootstrap will jump somewhere random");
+        }
+        run_program(&output_path, options);
+    }
+
+    program
+}
+
+/// the immediate subdirectories of `input_path` in name order, for `--per-subdir`
+fn collect_subdirs(input_path: &Path) -> Vec<PathBuf> {
+    let mut subdirs: Vec<PathBuf> = read_dir(input_path)
+        .unwrap_or_else(|e| {
+            eprintln!("ERROR: {e}");
+            process::exit(2);
+        })
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+        .collect();
+
+    subdirs.sort();
+    subdirs
+}
+
+/// parses every input without translating it. like translating, every bad line of the first
+/// file with any is reported before exiting
+fn parse_inputs(inputs: &[PathBuf]) -> Vec<(usize, Command)> {
+    let mut commands = Vec::new();
+
+    for path in inputs {
+        let mut parser = Parser::from_path(path).unwrap_or_else(|e| {
+            eprintln!("Error while reading input file: {}", e);
+            process::exit(1);
+        });
+
+        let (parsed, errors) = parser.parse_all_recovering();
+        for e in &errors {
+            eprintln!("ERROR: {} {e}", path.display());
+        }
+        if !errors.is_empty() {
+            process::exit(1);
+        }
+        commands.extend(parsed);
+    }
+
+    commands
+}
+
+/// the namespace commands typed into `--repl` are translated in
+const REPL_NAMESPACE: &str = "Repl";
+
+/// translates, assembles and runs every program in `SELFTEST_SAMPLES`, printing whether each
+/// ended with the RAM it should have and exiting with 1 if any didn't
+fn selftest() -> ! {
+    let mut failed = 0;
+
+    for Sample {
+        name,
+        source,
+        expected,
+    } in SELFTEST_SAMPLES
+    {
+        let mut writer = CodeWriter::new(Vec::new());
+        writer.set_warning_sink(Box::new(io::sink()));
+        writer.set_namespace("Sys".to_string());
+
+        let asm = writer
+            .write_init()
+            .map_err(|e| e.to_string())
+            .and_then(|_| hack_vm::translate(source, &mut writer).map_err(|e| e.to_string()))
+            .and_then(|_| writer.write_end().map_err(|e| e.to_string()))
+            .map(|_| String::from_utf8_lossy(writer.get_mut()).into_owned());
+        let rom = asm.and_then(|asm| assembler::assemble(&asm).map_err(|e| e.to_string()));
+
+        let result = rom.and_then(|rom| {
+            let mut simulator = Simulator::new(rom);
+            simulator.run(DEFAULT_MAX_CYCLES);
+            if !simulator.halted() {
+                return Err(format!("didn't halt within {DEFAULT_MAX_CYCLES} cycles"));
+            }
+
+            let wrong: Vec<_> = expected
+                .iter()
+                .filter(|(address, value)| simulator.ram[*address] != *value)
+                .map(|(address, value)| {
+                    format!(
+                        "RAM[{address}] = {}, expected {value}",
+                        simulator.ram[*address]
+                    )
+                })
+                .collect();
+            if wrong.is_empty() {
+                Ok(())
+            } else {
+                Err(wrong.join(", "))
+            }
+        });
+
+        match result {
+            Ok(()) => println!("{name}: ok"),
+            Err(e) => {
+                println!("{name}: FAILED, {e}");
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        println!("{failed} of {} samples failed", SELFTEST_SAMPLES.len());
+        process::exit(1);
+    }
+    println!("all {} samples passed", SELFTEST_SAMPLES.len());
+    process::exit(0);
+}
+
+/// translates VM commands from stdin as they're typed, for `--repl`. with `--run` everything
+/// translated so far is rerun from the start after each line and the stack is printed
+fn repl(options: &Options) {
+    let mut writer = CodeWriter::new(Vec::new());
+    configure_writer(&mut writer, options);
+    writer.set_namespace(REPL_NAMESPACE.to_string());
+    // there's no end of the program to put the shared comparison code after, and `--run` needs
+    // every comparison to work as soon as it's typed
+    writer.set_inline_compares(true);
+
+    let mut printed = 0;
+    let mut print_new = |writer: &mut CodeWriter<Vec<u8>>| {
+        let asm = writer.get_mut();
+        print!("{}", String::from_utf8_lossy(&asm[printed..]));
+        printed = asm.len();
+    };
+    if !options.no_sp_init {
+        writer.write_sp_init().unwrap_or_else(|e| {
+            eprintln!("ERROR: {e}");
+            process::exit(2);
+        });
+        print_new(&mut writer);
+    }
+
+    let stdin = io::stdin();
+    let mut line = String::new();
+    loop {
+        print!("vm> ");
+        io::stdout().flush().unwrap_or_default();
+        line.clear();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("ERROR: {e}");
+                process::exit(2);
+            }
+        }
+
+        let result = hack_vm::translate(&line, &mut writer);
+        print_new(&mut writer);
+        if let Err(e) = result {
+            println!("error: {}", e.message());
+            continue;
+        }
+
+        if options.run {
+            let asm = String::from_utf8_lossy(writer.get_mut()).into_owned();
+            let rom = match assembler::assemble(&asm) {
+                Ok(rom) => rom,
+                Err(e) => {
+                    println!("error: couldn't assemble, {e}");
+                    continue;
+                }
+            };
+            let mut simulator = Simulator::new(rom);
+            simulator.run(options.max_cycles.unwrap_or(DEFAULT_MAX_CYCLES));
+
+            let sp = simulator.ram[0].max(256) as usize;
+            let stack = &simulator.ram[256..sp.min(simulator.ram.len())];
+            println!("stack: {stack:?}");
+        }
+    }
+    println!();
+}
+
+fn main() {
+    let options = Options::parse(env::args().skip(1));
+    if options.selftest {
+        selftest();
+    }
+    if options.repl {
+        repl(&options);
+        return;
+    }
+    let input_path = Path::new(&options.input).to_path_buf();
+    let output_path = options
+        .output
+        .clone()
+        .unwrap_or_else(|| FileInfo::default_output_path(&input_path));
+
+    if options.count_only {
+        let commands = parse_inputs(&collect_inputs(&input_path, &options));
+        for (command, count) in analysis::count_commands(&commands) {
+            println!("{command}: {count}");
+        }
+        return;
+    }
 
-        parser = Parser::new(input_file);
-        writer.set_namespace(namespace);
+    // each program is its input files and where its output goes
+    let mut programs = vec![(collect_inputs(&input_path, &options), output_path.clone())];
+    if options.per_subdir && input_path.is_dir() {
+        let output_dir = output_path.parent().unwrap_or(Path::new(""));
+
+        for subdir in collect_subdirs(&input_path) {
+            let subdir_output = output_dir.join(FileInfo::default_output_path(&subdir));
+            programs.push((collect_inputs(&subdir, &options), subdir_output));
+        }
+        // the root only counts as a program if it has files of its own
+        programs.retain(|(inputs, _)| !inputs.is_empty());
+    }
+
+    let all_inputs: Vec<PathBuf> = programs
+        .iter()
+        .flat_map(|(inputs, _)| inputs.iter().cloned())
+        .collect();
+    for (_, output_path) in &programs {
+        check_output_path(output_path, &all_inputs);
+        check_output_dir(output_path, options.mkdir);
+    }
 
-        translate_file(&mut writer, &mut parser);
+    if let Some(path) = &options.bootstrap_file {
+        check_output_path(path, &all_inputs);
+        check_output_dir(path, options.mkdir);
+        write_bootstrap_file(path, &options);
     }
 
-    writer.write_end().unwrap();
+    let mut profile = options.profile.as_deref().map(Profile::create);
+    let mut commands = Vec::new();
+    for (inputs, output_path) in programs {
+        commands.extend(translate_program(
+            inputs,
+            output_path,
+            &options,
+            profile.as_mut(),
+        ));
+    }
+
+    if let Some(path) = &options.callgraph {
+        let dot = analysis::call_graph_dot(&analysis::call_graph(&commands));
+        fs::write(path, dot).unwrap_or_else(|e| {
+            eprintln!("Error writing call graph: {}", e);
+            process::exit(1);
+        });
+    }
+
+    if let Some(mut profile) = profile {
+        profile.out.flush().unwrap_or_else(|e| {
+            eprintln!("Error writing to profile file: {}", e);
+            process::exit(1);
+        });
+    }
 }
@@ -0,0 +1,116 @@
+//! a Hack CPU simulator for running assembled programs, see `assembler::assemble`
+
+/// words of RAM, up to and including the keyboard register
+const RAM_SIZE: usize = 24577;
+
+#[derive(Debug, Clone)]
+pub struct Simulator {
+    rom: Vec<u16>,
+    pub ram: Vec<i16>,
+    pub pc: u16,
+    pub a: i16,
+    pub d: i16,
+    halted: bool,
+}
+
+impl Simulator {
+    pub fn new(rom: Vec<u16>) -> Simulator {
+        Simulator {
+            rom,
+            ram: vec![0; RAM_SIZE],
+            pc: 0,
+            a: 0,
+            d: 0,
+            halted: false,
+        }
+    }
+
+    /// whether the program has stopped, either by running off the end of the ROM or by reaching
+    /// an end loop (`(END) @END 0;JMP`), which is how Hack programs finish
+    pub fn halted(&self) -> bool {
+        self.halted || self.pc as usize >= self.rom.len()
+    }
+
+    /// the value of RAM at `address`, 0 for addresses outside of it
+    fn read(&self, address: i16) -> i16 {
+        self.ram.get(address as u16 as usize).copied().unwrap_or(0)
+    }
+
+    /// computes the ALU output for the 6 `c` bits of an instruction, the same way the hardware
+    /// does: optionally zero and negate each input, add or and them, then optionally negate
+    fn alu(c_bits: u16, x: i16, y: i16) -> i16 {
+        let bit = |n: u16| c_bits & (1 << n) != 0;
+
+        let x = if bit(5) { 0 } else { x };
+        let x = if bit(4) { !x } else { x };
+        let y = if bit(3) { 0 } else { y };
+        let y = if bit(2) { !y } else { y };
+        let out = if bit(1) { x.wrapping_add(y) } else { x & y };
+        if bit(0) {
+            !out
+        } else {
+            out
+        }
+    }
+
+    /// executes one instruction, doing nothing once halted
+    pub fn step(&mut self) {
+        if self.halted() {
+            return;
+        }
+        let pc = self.pc;
+        let instruction = self.rom[pc as usize];
+
+        if instruction & (1 << 15) == 0 {
+            self.a = instruction as i16;
+            self.pc += 1;
+            return;
+        }
+
+        let y = if instruction & (1 << 12) != 0 {
+            self.read(self.a)
+        } else {
+            self.a
+        };
+        let out = Self::alu((instruction >> 6) & 0b111111, self.d, y);
+
+        // M is written to the address A held before this instruction
+        let address = self.a as u16 as usize;
+        if instruction & (1 << 3) != 0 {
+            if let Some(word) = self.ram.get_mut(address) {
+                *word = out;
+            }
+        }
+        if instruction & (1 << 5) != 0 {
+            self.a = out;
+        }
+        if instruction & (1 << 4) != 0 {
+            self.d = out;
+        }
+
+        let jump = (instruction & 0b100 != 0 && out < 0)
+            || (instruction & 0b010 != 0 && out == 0)
+            || (instruction & 0b001 != 0 && out > 0);
+        if !jump {
+            self.pc += 1;
+            return;
+        }
+
+        let target = address as u16;
+        // jumping back to the `@END` right before this jump can only ever loop forever
+        self.halted =
+            target.wrapping_add(1) == pc && self.rom.get(target as usize) == Some(&target);
+        self.pc = target;
+    }
+
+    /// steps until the program halts or `max_cycles` instructions have run, returning how many
+    /// instructions were run
+    pub fn run(&mut self, max_cycles: usize) -> usize {
+        let mut cycles = 0;
+        while cycles < max_cycles && !self.halted() {
+            self.step();
+            cycles += 1;
+        }
+        cycles
+    }
+}
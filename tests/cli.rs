@@ -0,0 +1,683 @@
+//! runs the hack-vm binary over files on disk, for the options that only the command line has
+
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+};
+
+/// an empty directory for one test's files, named after the test so they can run in parallel
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("hack-vm-test-{name}"));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    dir
+}
+
+/// runs hack-vm in `dir` with `args`, so anything it writes by default stays in there
+fn hack_vm(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_hack-vm"))
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn read(path: impl AsRef<Path>) -> String {
+    fs::read_to_string(path).unwrap()
+}
+
+#[test]
+fn profile_wraps_commands_and_writes_the_sidecar() {
+    let dir = temp_dir("profile");
+    fs::write(dir.join("P.vm"), "push constant 1\npush constant 2\nadd\n").unwrap();
+
+    let output = hack_vm(&dir, &["P.vm", "--no-bootstrap", "--profile", "P.prof"]);
+    assert!(output.status.success());
+
+    let asm = read(dir.join("P.asm"));
+    let labels: Vec<&str> = asm
+        .lines()
+        .filter(|line| line.starts_with("(__vm"))
+        .collect();
+    assert_eq!(
+        labels,
+        [
+            "(__vm_start.0)",
+            "(__vm_end.0)",
+            "(__vm_start.1)",
+            "(__vm_end.1)",
+            "(__vm_start.2)",
+            "(__vm_end.2)",
+        ]
+    );
+    assert_eq!(
+        read(dir.join("P.prof")),
+        "0\tP.vm\t1\tpush constant 1\n1\tP.vm\t2\tpush constant 2\n2\tP.vm\t3\tadd\n"
+    );
+}
+
+#[test]
+fn output_colliding_with_an_input_is_rejected() {
+    let dir = temp_dir("collide");
+    let source = "push constant 1\n";
+    fs::write(dir.join("P.vm"), source).unwrap();
+
+    let output = hack_vm(&dir, &["P.vm", "-o", "./P.vm"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("output file `./P.vm` is also an input file"));
+    assert_eq!(read(dir.join("P.vm")), source);
+}
+
+#[test]
+fn translating_twice_gives_the_same_output() {
+    let dir = temp_dir("idempotent");
+    let source = "function Sys.init 0\npush constant 1\npush constant 2\nlt\nreturn\n";
+    fs::write(dir.join("Sys.vm"), source).unwrap();
+    // left over from a run that was cut short, longer than the real output
+    fs::write(dir.join("Sys.asm"), "@partial\n".repeat(1000)).unwrap();
+
+    assert!(hack_vm(&dir, &["Sys.vm"]).status.success());
+    let first = read(dir.join("Sys.asm"));
+    assert!(hack_vm(&dir, &["Sys.vm"]).status.success());
+
+    assert!(!first.contains("@partial"));
+    assert_eq!(read(dir.join("Sys.asm")), first);
+}
+
+#[test]
+fn no_sp_init_keeps_the_entry_call() {
+    let dir = temp_dir("no-sp-init");
+    fs::write(
+        dir.join("Sys.vm"),
+        "function Sys.init 0\nlabel END\ngoto END\n",
+    )
+    .unwrap();
+
+    assert!(hack_vm(&dir, &["Sys.vm"]).status.success());
+    assert!(read(dir.join("Sys.asm")).starts_with("@256\nD=A\n@SP\nM=D\n@Sys.init\n0;JMP\n"));
+
+    assert!(hack_vm(&dir, &["Sys.vm", "--no-sp-init"]).status.success());
+    let asm = read(dir.join("Sys.asm"));
+    assert!(asm.starts_with("@Sys.init\n0;JMP\n"));
+    assert!(!asm.contains("@256"));
+}
+
+#[test]
+fn annotate_depth_follows_each_command() {
+    let dir = temp_dir("annotate-depth");
+    fs::write(dir.join("D.vm"), "push constant 1\npush constant 2\nadd\n").unwrap();
+
+    let output = hack_vm(&dir, &["D.vm", "--no-bootstrap", "--annotate-depth"]);
+    assert!(output.status.success());
+
+    let asm = read(dir.join("D.asm"));
+    let depths: Vec<&str> = asm
+        .lines()
+        .filter_map(|line| line.strip_prefix("// depth: "))
+        .collect();
+    assert_eq!(depths, ["1", "2", "1"]);
+}
+
+#[test]
+fn per_subdir_translates_each_subdirectory_on_its_own() {
+    let dir = temp_dir("per-subdir");
+    for subdir in ["A", "B"] {
+        fs::create_dir_all(dir.join("Prog").join(subdir)).unwrap();
+    }
+    let sys = "function Sys.init 0\npush static 0\nlabel END\ngoto END\n";
+    fs::write(dir.join("Prog/A/Sys.vm"), sys).unwrap();
+    fs::write(dir.join("Prog/B/Sys.vm"), sys).unwrap();
+    fs::write(dir.join("Prog/Main.vm"), "function Main.main 0\nreturn\n").unwrap();
+
+    assert!(hack_vm(&dir, &["Prog", "--per-subdir"]).status.success());
+
+    for program in ["A", "B"] {
+        let asm = read(dir.join(format!("{program}.asm")));
+        assert!(asm.starts_with("@256\nD=A\n@SP\nM=D\n@Sys.init\n0;JMP\n"));
+        assert_eq!(asm.matches("(Sys.init)").count(), 1);
+        assert!(asm.contains("@Sys.0"));
+        assert!(!asm.contains("Main.main"));
+    }
+    // the files directly in the input directory are a program of their own
+    let root = read(dir.join("Prog.asm"));
+    assert!(root.contains("(Main.main)"));
+    assert!(!root.contains("(Sys.init)"));
+}
+
+#[test]
+fn count_only_prints_counts_without_translating() {
+    let dir = temp_dir("count-only");
+    fs::write(dir.join("P.vm"), "push constant 1\npush constant 2\nadd\n").unwrap();
+
+    let output = hack_vm(&dir, &["P.vm", "--count-only"]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "add: 1\npush: 2\n");
+    assert!(!dir.join("P.asm").exists());
+}
+
+#[test]
+fn list_statics_maps_symbols_to_their_first_use() {
+    let dir = temp_dir("list-statics");
+    fs::create_dir_all(dir.join("P")).unwrap();
+    let main = "function Main.main 0\npush static 2\npush static 0\npop static 2\nreturn\n";
+    fs::write(dir.join("P/Main.vm"), main).unwrap();
+    let sys = "function Sys.init 0\npop static 0\nlabel END\ngoto END\n";
+    fs::write(dir.join("P/Sys.vm"), sys).unwrap();
+
+    let output = hack_vm(&dir, &["P", "--list-statics", "--deterministic"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "Main.0: Main.vm line 3\nMain.2: Main.vm line 2\nSys.0: Sys.vm line 2\n"
+    );
+}
+
+#[test]
+fn max_rom_fails_before_anything_else_uses_the_output() {
+    let dir = temp_dir("max-rom");
+    fs::write(dir.join("P.vm"), "push constant 1\npush constant 2\nadd\n").unwrap();
+
+    let args = [
+        "P.vm",
+        "--no-bootstrap",
+        "--max-rom",
+        "10",
+        "--emit-sym",
+        "--run",
+    ];
+    let output = hack_vm(&dir, &args);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("the program needs 28 words of ROM, 18 more than the 10 available"));
+    assert!(!dir.join("P.asm").exists());
+    assert!(!dir.join("P.sym").exists());
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("RAM["));
+
+    let output = hack_vm(&dir, &["P.vm", "--no-bootstrap", "--max-rom", "28"]);
+    assert!(output.status.success());
+}
+
+#[test]
+fn missing_output_directory_needs_mkdir() {
+    let dir = temp_dir("mkdir");
+    fs::write(dir.join("P.vm"), "push constant 1\n").unwrap();
+
+    let output = hack_vm(&dir, &["P.vm", "-o", "build/out/P.asm"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("output directory `build/out` does not exist; pass --mkdir to create"));
+    assert!(!dir.join("build").exists());
+
+    let output = hack_vm(&dir, &["P.vm", "-o", "build/out/P.asm", "--mkdir"]);
+    assert!(output.status.success());
+    assert!(read(dir.join("build/out/P.asm")).contains("@1\n"));
+}
+
+#[test]
+fn deterministic_output_is_byte_identical() {
+    let dir = temp_dir("deterministic");
+    fs::create_dir_all(dir.join("Prog")).unwrap();
+    let files = [
+        (
+            "Main.vm",
+            "function Main.main 0\npush static 0\npush constant 1\nlt\nreturn\n",
+        ),
+        (
+            "Sys.vm",
+            "function Sys.init 0\ncall Main.main 0\nlabel END\ngoto END\n",
+        ),
+        (
+            "Util.vm",
+            "function Util.eq 0\npush argument 0\npush argument 1\neq\nreturn\n",
+        ),
+    ];
+    for (name, source) in files {
+        fs::write(dir.join("Prog").join(name), source).unwrap();
+    }
+
+    let args = ["Prog", "--deterministic", "-o", "first.asm"];
+    let first = hack_vm(&dir, &args);
+    let args = ["Prog", "--deterministic", "-o", "second.asm"];
+    let second = hack_vm(&dir, &args);
+
+    assert!(first.status.success() && second.status.success());
+    assert_eq!(
+        fs::read(dir.join("first.asm")).unwrap(),
+        fs::read(dir.join("second.asm")).unwrap()
+    );
+    // nothing that changes from run to run, like progress, is printed either
+    assert!(first.stdout.is_empty() && second.stdout.is_empty());
+}
+
+#[test]
+fn function_named_like_a_static_is_detected() {
+    let dir = temp_dir("static-collision");
+    fs::write(
+        dir.join("Main.vm"),
+        "function Main.0 0\npush static 0\nreturn\n",
+    )
+    .unwrap();
+
+    let output = hack_vm(&dir, &["Main.vm"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains(
+        "function `Main.0` (Main.vm line 1) has the same symbol as a static variable \
+         (Main.vm line 2)"
+    ));
+    assert!(!dir.join("Main.asm").exists());
+
+    fs::write(
+        dir.join("Main.vm"),
+        "function Main.1 0\npush static 0\nreturn\n",
+    )
+    .unwrap();
+    assert!(hack_vm(&dir, &["Main.vm"]).status.success());
+}
+
+#[test]
+fn bootstrap_file_takes_the_bootstrap_out_of_the_output() {
+    let dir = temp_dir("bootstrap-file");
+    fs::write(
+        dir.join("Sys.vm"),
+        "function Sys.init 0\nlabel END\ngoto END\n",
+    )
+    .unwrap();
+
+    let output = hack_vm(&dir, &["Sys.vm", "--bootstrap-file", "boot.asm"]);
+    assert!(output.status.success());
+
+    let bootstrap = read(dir.join("boot.asm"));
+    assert!(bootstrap.contains("@256") && bootstrap.contains("@Sys.init"));
+    assert!(!bootstrap.contains("(Sys.init)"));
+    let asm = read(dir.join("Sys.asm"));
+    assert!(!asm.contains("@256"));
+    assert!(asm.starts_with("(Sys.init)"));
+}
+
+#[test]
+fn run_prints_the_watched_ram() {
+    let dir = temp_dir("run");
+    fs::write(dir.join("P.vm"), "push constant 7\npush constant 8\nadd\n").unwrap();
+
+    let args = [
+        "P.vm",
+        "--no-bootstrap",
+        "--run",
+        "--watch",
+        "256",
+        "--deterministic",
+    ];
+    let output = hack_vm(&dir, &args);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "halted after 28 cycles\nRAM[256] = 15\n"
+    );
+}
+
+#[test]
+fn exclude_skips_matching_files() {
+    let dir = temp_dir("exclude");
+    fs::create_dir_all(dir.join("Prog")).unwrap();
+    for name in ["Main", "Sys", "MainTest"] {
+        let source = format!("function {name}.f 0\npush constant 0\nreturn\n");
+        fs::write(dir.join("Prog").join(format!("{name}.vm")), source).unwrap();
+    }
+
+    let output = hack_vm(&dir, &["Prog", "--exclude", "*Test.vm", "--deterministic"]);
+    assert!(output.status.success());
+
+    let asm = read(dir.join("Prog.asm"));
+    assert!(asm.contains("(Main.f)") && asm.contains("(Sys.f)"));
+    assert!(!asm.contains("MainTest"));
+}
+
+#[test]
+fn emit_sym_writes_the_resolved_symbols() {
+    let dir = temp_dir("emit-sym");
+    let source = "function Sys.init 0\npush static 3\npop static 1\nlabel END\ngoto END\n";
+    fs::write(dir.join("Sys.vm"), source).unwrap();
+
+    assert!(hack_vm(&dir, &["Sys.vm", "--emit-sym"]).status.success());
+
+    let symbols = read(dir.join("Sys.sym"));
+    let lines: Vec<&str> = symbols.lines().collect();
+    // the bootstrap takes the first 6 words of ROM
+    assert!(lines.contains(&"Sys.init 6"));
+    assert!(lines.contains(&"Sys.3 16"));
+    assert!(lines.contains(&"Sys.1 17"));
+    assert!(lines.contains(&"SP 0"));
+}
+
+#[test]
+fn path_namespace_keeps_same_named_files_apart() {
+    let dir = temp_dir("path-namespace");
+    for subdir in ["sub1", "sub2"] {
+        fs::create_dir_all(dir.join("P").join(subdir)).unwrap();
+        let source = format!("function Main.{subdir} 0\npush static 0\nreturn\n");
+        fs::write(dir.join("P").join(subdir).join("Main.vm"), source).unwrap();
+    }
+
+    let args = ["P", "--recursive", "--no-bootstrap", "--deterministic"];
+    assert!(hack_vm(&dir, &args).status.success());
+    assert_eq!(read(dir.join("P.asm")).matches("@Main.0\n").count(), 2);
+
+    let args = [
+        "P",
+        "--recursive",
+        "--path-namespace",
+        "--no-bootstrap",
+        "--deterministic",
+    ];
+    assert!(hack_vm(&dir, &args).status.success());
+    let asm = read(dir.join("P.asm"));
+    assert!(asm.contains("@sub1.Main.0\n") && asm.contains("@sub2.Main.0\n"));
+    assert!(!asm.contains("@Main.0\n"));
+}
+
+/// runs `hack-vm --repl` with `args` on top, typing `input` into it
+fn repl(args: &[&str], input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_hack-vm"))
+        .arg("--repl")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn repl_translates_line_by_line() {
+    let output = repl(&[], "push constant 7\npush constant 8\n");
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let first = stdout.find("// push constant 7").unwrap();
+    let second = stdout.find("// push constant 8").unwrap();
+    assert!(first < second);
+    assert_eq!(stdout.matches("vm> ").count(), 3);
+
+    // the function a line is in carries over to the lines after it
+    let output = repl(&[], "function Foo.f 0\nlabel L\n");
+    assert!(String::from_utf8_lossy(&output.stdout).contains("(Foo.f$L)"));
+}
+
+#[test]
+fn repl_runs_comparisons() {
+    let output = repl(
+        &["--run"],
+        "push constant 1\npush constant 2\nlt\npush constant 3\n",
+    );
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stacks: Vec<&str> = stdout
+        .lines()
+        .filter_map(|line| line.strip_prefix("stack: "))
+        .collect();
+    assert_eq!(stacks, ["[1]", "[1, 2]", "[-1]", "[-1, 3]"]);
+}
+
+#[test]
+fn jobs_output_matches_sequential_output() {
+    let dir = temp_dir("jobs");
+    fs::create_dir_all(dir.join("Prog")).unwrap();
+    for (i, name) in ["Main", "Sys", "Math", "Util", "Screen"].iter().enumerate() {
+        let source = format!(
+            "function {name}.f {i}\npush static {i}\npush constant {i}\ngt\ncall {name}.f 1\nreturn\n"
+        );
+        fs::write(dir.join("Prog").join(format!("{name}.vm")), source).unwrap();
+    }
+
+    let sequential = hack_vm(&dir, &["Prog", "--deterministic", "-o", "sequential.asm"]);
+    let parallel = hack_vm(
+        &dir,
+        &[
+            "Prog",
+            "--deterministic",
+            "--jobs",
+            "3",
+            "-o",
+            "parallel.asm",
+        ],
+    );
+
+    assert!(sequential.status.success() && parallel.status.success());
+    assert_eq!(
+        read(dir.join("parallel.asm")),
+        read(dir.join("sequential.asm"))
+    );
+}
+
+#[test]
+fn file_without_a_trailing_newline() {
+    let dir = temp_dir("no-newline");
+    fs::write(dir.join("P.vm"), "push constant 7\npush constant 8\nadd").unwrap();
+
+    let args = ["P.vm", "--no-bootstrap", "--run", "--deterministic"];
+    let output = hack_vm(&dir, &args);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).ends_with("RAM[0] = 257\nRAM[256] = 15\n"));
+}
+
+#[test]
+fn max_function_size_warns_about_large_functions() {
+    let dir = temp_dir("max-function-size");
+    let big =
+        "function Main.big 0\n".to_string() + &"push constant 1\nadd\n".repeat(20) + "return\n";
+    let small = "function Main.small 0\npush constant 0\nreturn\n";
+    fs::write(dir.join("Main.vm"), big + small).unwrap();
+
+    let output = hack_vm(&dir, &["Main.vm", "--max-function-size", "100"]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Warning: function `Main.big` is "));
+    assert!(stderr.contains("instructions, over the limit of 100"));
+    assert!(!stderr.contains("Main.small"));
+}
+
+#[test]
+fn listing_maps_addresses_to_vm_lines() {
+    let dir = temp_dir("listing");
+    fs::write(dir.join("P.vm"), "push constant 7\npush constant 8\nadd\n").unwrap();
+
+    let args = [
+        "P.vm",
+        "--no-bootstrap",
+        "-o",
+        "P.asm",
+        "--listing",
+        "P.lst",
+    ];
+    let output = hack_vm(&dir, &args);
+    assert!(output.status.success());
+
+    let listing = read(dir.join("P.lst"));
+    let line = |address: &str| {
+        listing
+            .lines()
+            .find(|line| line.split_whitespace().next() == Some(address))
+            .unwrap_or_else(|| panic!("no listing line for address {address}"))
+            .split_whitespace()
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(line("0"), ["0", "@7", "P.vm:1", "push", "constant", "7"]);
+    assert_eq!(line("7"), ["7", "@8", "P.vm:2", "push", "constant", "8"]);
+    assert_eq!(line("14"), ["14", "@SP", "P.vm:3", "add"]);
+    assert!(listing.contains("(VMEND)"));
+}
+
+#[test]
+fn rename_changes_the_static_namespace() {
+    let dir = temp_dir("rename");
+    fs::write(dir.join("Old.vm"), "push constant 3\npop static 1\n").unwrap();
+
+    let args = [
+        "Old.vm",
+        "--no-bootstrap",
+        "-o",
+        "P.asm",
+        "--rename",
+        "Old=New",
+    ];
+    let output = hack_vm(&dir, &args);
+    assert!(output.status.success());
+    let asm = read(dir.join("P.asm"));
+    assert!(asm.contains("@New.1\n"));
+    assert!(!asm.contains("@Old.1"));
+
+    let output = hack_vm(&dir, &["Old.vm", "-o", "Q.asm", "--rename", "Old=1bad"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Invalid value for --rename"));
+}
+
+#[test]
+fn library_has_no_bootstrap_or_end_loop() {
+    let dir = temp_dir("library");
+    fs::write(
+        dir.join("Lib.vm"),
+        "function Lib.id 0\npush argument 0\nreturn\n",
+    )
+    .unwrap();
+
+    let output = hack_vm(&dir, &["Lib.vm", "--library", "-o", "Lib.asm"]);
+    assert!(output.status.success());
+    let asm = read(dir.join("Lib.asm"));
+    assert!(asm.starts_with("(Lib.id)\n"));
+    assert!(!asm.contains("@256"));
+    assert!(!asm.contains("(VMEND)"));
+
+    let output = hack_vm(&dir, &["Lib.vm", "--library", "--run", "-o", "Run.asm"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--library has no entry point"));
+}
+
+#[test]
+fn compare_inline_threshold_inlines_only_below_it() {
+    let dir = temp_dir("compare-inline-threshold");
+    let source = "push constant 1\npush constant 2\nlt\npush constant 3\npush constant 3\neq\n";
+    fs::write(dir.join("P.vm"), source).unwrap();
+
+    // the program has 2 comparisons, inlined under a threshold of 3 but shared at 2
+    for (threshold, inlined) in [("3", true), ("2", false)] {
+        let output_file = format!("P{threshold}.asm");
+        let args = [
+            "P.vm",
+            "--no-bootstrap",
+            "--compare-inline-threshold",
+            threshold,
+            "-o",
+            &output_file,
+            "--run",
+            "--deterministic",
+            "--watch",
+            "256",
+            "--watch",
+            "257",
+        ];
+        let output = hack_vm(&dir, &args);
+        assert!(output.status.success());
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(stdout.contains("RAM[256] = -1\nRAM[257] = -1\n"));
+
+        let asm = read(dir.join(&output_file));
+        assert_eq!(
+            !asm.contains("(__CMP_TRUE)"),
+            inlined,
+            "threshold {threshold}"
+        );
+    }
+}
+
+#[test]
+fn validate_only_segment_indices_reports_each_segment() {
+    let dir = temp_dir("validate-only-segment-indices");
+    let cases = [
+        ("push pointer 2", "expected 0 or 1 for segment 'pointer'"),
+        ("pop temp 8", "expected 0..=7 for segment 'temp'"),
+        ("push static 240", "expected 0..=239 for segment 'static'"),
+        ("push local -1", "expected a non-negative index"),
+        (
+            "push constant 40000",
+            "index 40000 is out of bounds for segment 'constant', expected 0..=32767",
+        ),
+    ];
+
+    for (command, expected) in cases {
+        fs::write(dir.join("P.vm"), format!("push constant 1\n{command}\n")).unwrap();
+        let args = ["P.vm", "--validate-only-segment-indices", "-o", "P.asm"];
+        let output = hack_vm(&dir, &args);
+
+        assert!(!output.status.success(), "{command}");
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("P.vm line 2: "), "{command}: {stderr}");
+        assert!(stderr.contains(expected), "{command}: {stderr}");
+    }
+
+    // without the flag a large constant only warns
+    let output = hack_vm(&dir, &["P.vm", "-o", "P.asm"]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is over 32767"));
+}
+
+#[test]
+fn selftest_passes_every_sample() {
+    let dir = temp_dir("selftest");
+    let output = hack_vm(&dir, &["--selftest"]);
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.lines().any(|line| line == "SimpleAdd: ok"));
+    assert!(stdout.ends_with("all 4 samples passed\n"));
+    // nothing is written to the working directory
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+}
+
+#[test]
+fn failed_translation_leaves_no_output() {
+    let dir = temp_dir("failed-translation");
+    fs::write(dir.join("M.vm"), "foo\npush nowhere 1\nbar\n").unwrap();
+
+    let output = hack_vm(&dir, &["M.vm"]);
+    assert!(!output.status.success());
+    assert!(!dir.join("M.asm").exists());
+
+    // the same goes for a directory translated on several threads
+    let program = dir.join("Prog");
+    fs::create_dir(&program).unwrap();
+    fs::write(program.join("A.vm"), "push constant 1\n").unwrap();
+    fs::write(program.join("B.vm"), "push constant 1\nfoo\n").unwrap();
+
+    let output = hack_vm(&dir, &["Prog", "--jobs", "2", "-o", "Prog.asm"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("unrecognized command `foo`"));
+    assert!(!dir.join("Prog.asm").exists());
+}
+
+#[test]
+fn compare_inline_threshold_reports_every_bad_line() {
+    let dir = temp_dir("compare-inline-threshold-errors");
+    fs::write(dir.join("M.vm"), "foo\npush nowhere 1\nbar\n").unwrap();
+
+    let args = ["M.vm", "--compare-inline-threshold", "3"];
+    let output = hack_vm(&dir, &args);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("line 1: unrecognized command `foo`"));
+    assert!(stderr.contains("line 2: unknown segment `nowhere`"));
+    assert!(stderr.contains("line 3: unrecognized command `bar`"));
+    assert!(!dir.join("M.asm").exists());
+}